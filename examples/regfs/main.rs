@@ -1,21 +1,38 @@
-use anyhow::Result;
-use prjfs::provider::{Provider, ProviderT};
-use prjfs::{NotificationType, OptionBuilder};
-
-mod dirinfo;
+#[cfg(windows)]
 mod regfs;
+#[cfg(windows)]
 mod regop;
 
-use crate::regfs::RegFs;
+#[cfg(windows)]
+fn main() -> anyhow::Result<()> {
+    use crate::regfs::RegFs;
+    use prjfs::provider::{Provider, ProviderT};
+    use prjfs::{NotificationType, OptionBuilder, RootConfig};
 
-fn main() -> Result<()> {
     env_logger::init();
-    let options = OptionBuilder::new().add_root_notification(
-        NotificationType::FILE_OPENED | NotificationType::PRE_RENAME | NotificationType::PRE_DELETE,
-    );
+    // an optional .toml or .json file replaces the default options
+    let options = match std::env::args_os().nth(1) {
+        Some(path) => OptionBuilder::read(path)?,
+        None => OptionBuilder::new().add_root_notification(
+            NotificationType::FILE_OPENED
+                | NotificationType::PRE_RENAME
+                | NotificationType::PRE_DELETE,
+        ),
+    };
     let regfs: Box<dyn ProviderT> = Box::new(RegFs::new());
 
-    let _provider = Provider::new("./test".into(), options, regfs)?;
+    let root = RootConfig::new("regfs", env!("CARGO_PKG_VERSION")).marker_file(".regfsId");
+
+    let provider = Provider::new("./test".into(), root, options, regfs)?;
+
+    println!("Virtualizing ./test, press Enter to stop");
+    std::io::stdin().read_line(&mut String::new())?;
+    provider.stop();
+
+    Ok(())
+}
 
-    loop {}
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("regfs requires Windows");
 }
@@ -1,354 +1,202 @@
-use anyhow::{anyhow, Result};
 use log::{info, warn};
-use prjfs::conv::{RawWStrExt, WStrExt};
-use prjfs::guid::guid_to_bytes;
-use prjfs::ProviderT;
-use std::{collections::HashMap, ffi::OsString, sync::Mutex};
-use winapi::{
-    shared::{
-        guiddef::GUID,
-        ntdef::TRUE,
-        winerror::{self, HRESULT_FROM_WIN32, S_OK},
-    },
-    um::{
-        projectedfslib::{
-            PRJ_CALLBACK_DATA, PRJ_DIR_ENTRY_BUFFER_HANDLE, PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
-            PRJ_NOTIFICATION_PARAMETERS, PRJ_PLACEHOLDER_INFO,
-        },
-        winnt::{HRESULT, LPCWSTR, PCWSTR},
-    },
+use prjfs::enumeration::{DirEntry, DirectorySource, SessionTable};
+use prjfs::{
+    CallbackContext, DirEntryBuffer, Error, Guid, Notification, PlaceholderInfo, ProviderT, Result,
+    Verdict,
 };
+use std::{ffi::OsStr, path::Path};
 
-use crate::dirinfo::DirInfo;
 use crate::regop::RegOps;
 
-#[derive(Default)]
-pub struct State {
-    enum_sessions: HashMap<Vec<u8>, DirInfo>,
-}
-
 pub struct RegFs {
-    state: Mutex<State>,
+    sessions: SessionTable,
     regops: RegOps,
     readonly: bool,
-    context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
 }
 
 impl RegFs {
     pub fn new() -> Self {
         RegFs {
-            state: Mutex::new(Default::default()),
+            sessions: SessionTable::new(),
             regops: RegOps::new(),
             readonly: true,
-            context: std::ptr::null_mut(),
         }
     }
 }
 
-impl RegFs {
-    fn write_placeholder_info(&self, filepath: LPCWSTR, info: PRJ_PLACEHOLDER_INFO) -> HRESULT {
-        unsafe {
-            prjfs::sys::PrjWritePlaceholderInfo(
-                self.context,
-                filepath,
-                &info,
-                std::mem::size_of_val(&info) as u32,
-            )
-        }
-    }
-
-    fn populate_dir_info_for_path(
-        &self,
-        path: OsString,
-        dirinfo: &mut DirInfo,
-        search_expression: OsString,
-    ) -> bool {
-        let entries = if let Some(entries) = self.regops.enumerate_key(path) {
-            entries
-        } else {
-            return false;
+impl DirectorySource for RegFs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        let entries = match self.regops.enumerate_key(path.into()) {
+            Some(entries) => entries,
+            None => return Err(Error::not_found().with_message("failed to get key")),
         };
 
-        for subkey in entries.subkeys {
-            let result = unsafe {
-                prjfs::sys::PrjFileNameMatch(
-                    subkey.name.to_wstr().as_ptr(),
-                    search_expression.to_wstr().as_ptr(),
-                )
-            };
-
-            if result == TRUE {
-                dirinfo.fill_dir_entry(subkey.name);
-            }
-        }
-
-        for value in entries.values {
-            let result = unsafe {
-                prjfs::sys::PrjFileNameMatch(
-                    value.name.to_wstr().as_ptr(),
-                    search_expression.to_wstr().as_ptr(),
-                )
-            };
+        let subkeys = entries
+            .subkeys
+            .into_iter()
+            .map(|subkey| DirEntry::directory(subkey.name));
+        let values = entries
+            .values
+            .into_iter()
+            .map(|value| DirEntry::file(value.name, value.size));
 
-            if result == TRUE {
-                dirinfo.fill_file_entry(value.name, value.size as i64);
-            }
-        }
-
-        true
+        Ok(subkeys.chain(values).collect())
     }
 }
 
 impl ProviderT for RegFs {
-    fn get_context_mut(&mut self) -> Option<*mut prjfs::sys::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT> {
-        Some(&mut self.context)
-    }
-
-    fn start_dir_enum(
-        &self,
-        callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-    ) -> Result<HRESULT> {
-        let filepath = callback_data.FilePathName.to_os();
+    fn start_dir_enum(&self, context: &CallbackContext, enumeration_id: Guid) -> Result<()> {
         info!(
             "----> start_dir_enum: Path [{:?}] triggered by [{:?}]",
-            filepath,
-            callback_data.TriggeringProcessImageFileName.to_os()
+            context.file_path, context.triggering_process_image
         );
 
-        let guid = guid_to_bytes(enumeration_id);
-        self.state
-            .lock()
-            .unwrap()
-            .enum_sessions
-            .insert(guid, DirInfo::new(filepath));
+        self.sessions.start(enumeration_id, &context.file_path);
 
         info!("<---- start_dir_enum: return 0x0");
 
-        Ok(0)
+        Ok(())
     }
 
-    fn end_dir_enum(
-        &self,
-        _callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-    ) -> Result<HRESULT> {
+    fn end_dir_enum(&self, _context: &CallbackContext, enumeration_id: Guid) -> Result<()> {
         info!("----> end_dir_enum");
 
-        let guid = guid_to_bytes(enumeration_id);
-        let mut state = self
-            .state
-            .lock()
-            .map_err(|_| anyhow!("unable to acquire state"))?;
-
-        state.enum_sessions.remove(&guid);
+        self.sessions.end(enumeration_id);
 
         info!("<---- end_dir_enum: return 0x0");
-        Ok(0)
+        Ok(())
     }
 
     fn get_dir_enum(
         &self,
-        data: &PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-        search_expression: PCWSTR,
-        handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
-    ) -> Result<HRESULT> {
-        let path = data.FilePathName.to_os();
-        let search_expression = search_expression.to_os();
+        context: &CallbackContext,
+        enumeration_id: Guid,
+        search_expression: Option<&OsStr>,
+        buffer: &mut dyn DirEntryBuffer,
+    ) -> Result<()> {
         info!(
             "----> get_dir_enum: Path [{:?}] SearchExpression: [{:?}]",
-            path, search_expression
+            context.file_path, search_expression
         );
 
-        let guid = guid_to_bytes(enumeration_id);
-        let mut state = self
-            .state
-            .lock()
-            .map_err(|_| anyhow!("unable to acquire state"))?;
-
-        let dirinfo = match state.enum_sessions.get_mut(&guid) {
-            Some(session) => session,
-            None => return Ok(winerror::E_INVALIDARG),
-        };
-
-        if data.Flags & prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN != 0 {
-            dirinfo.reset();
-        }
-
-        if !dirinfo.filled() {
-            if !self.populate_dir_info_for_path(path, dirinfo, search_expression) {
-                return Err(anyhow!("failed to get key"));
-            }
-
-            dirinfo.sort_entries_and_mark_filled();
-        }
-
-        while dirinfo.current_is_valid() {
-            let result = unsafe {
-                prjfs::sys::PrjFillDirEntryBuffer(
-                    dirinfo.current_file_name().as_ptr(),
-                    &mut dirinfo.current_basic_info(),
-                    handle,
-                )
-            };
-
-            if result != S_OK {
-                break;
-            }
-
-            dirinfo.move_next();
-        }
+        self.sessions
+            .fill(self, context, enumeration_id, search_expression, buffer)?;
 
         info!("<---- get_dir_enum: return {:08x}", 0);
-        Ok(S_OK)
+        Ok(())
     }
 
-    fn get_placeholder_info(&self, data: &PRJ_CALLBACK_DATA) -> Result<HRESULT> {
-        let path = data.FilePathName.to_os();
+    fn get_placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo> {
+        let path = &context.file_path;
         info!(
             "----> get_placeholder_info: Path [{:?}] triggered by {:?}]",
-            path,
-            data.TriggeringProcessImageFileName.to_os()
+            path, context.triggering_process_image
         );
 
-        let size: Option<i64> = if self.regops.does_key_exist(path.as_ref()) {
-            None
-        } else if let Some(size) = self.regops.does_value_exist(path.as_ref()) {
-            Some(size as i64)
+        let placeholder = if self.regops.does_key_exist(path) {
+            PlaceholderInfo::directory()
+        } else if let Some(size) = self.regops.does_value_exist(path) {
+            PlaceholderInfo::file(size as u64)
         } else {
-            info!(
-                "<---- get_place_holder_info: return {:08x}",
-                winerror::ERROR_FILE_NOT_FOUND
-            );
-            return Ok(winerror::HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND));
+            info!("<---- get_place_holder_info: file not found");
+            return Err(Error::not_found());
         };
 
-        let mut placeholder = prjfs::sys::PRJ_PLACEHOLDER_INFO::default();
-        if let Some(size) = size {
-            placeholder.FileBasicInfo.IsDirectory = false as u8;
-            placeholder.FileBasicInfo.FileSize = size;
-        } else {
-            placeholder.FileBasicInfo.IsDirectory = true as u8;
-            placeholder.FileBasicInfo.FileSize = 0;
-        }
+        info!("<---- get_placeholder_info: {:?}", placeholder);
 
-        let result = self.write_placeholder_info(data.FilePathName, placeholder);
-
-        info!("<---- get_placeholder_info: {:08x}", result);
-
-        Ok(result)
+        Ok(placeholder)
     }
 
-    fn get_file_data(&self, data: &PRJ_CALLBACK_DATA, offset: u64, length: u32) -> Result<HRESULT> {
-        let path = data.FilePathName.to_os();
-        let process = data.TriggeringProcessImageFileName.to_os();
+    fn get_file_data(
+        &self,
+        context: &CallbackContext,
+        offset: u64,
+        length: u32,
+    ) -> Result<Vec<u8>> {
         info!(
             "----> get_file_data: Path[{:?}] triggered by [{:?}]",
-            path, process
+            context.file_path, context.triggering_process_image
         );
 
-        let rawbuffer =
-            unsafe { prjfs::sys::PrjAllocateAlignedBuffer(self.context, length as usize) };
-        if rawbuffer.is_null() {
-            warn!("<---- get_file_data: Could not allocate write buffer.");
-            return Ok(winerror::E_OUTOFMEMORY);
-        }
-        let buffer =
-            unsafe { std::slice::from_raw_parts_mut(rawbuffer as *mut u8, length as usize) };
-
-        let hr = if let Some(bytes) = self.regops.read_value(path.as_ref()) {
-            buffer.copy_from_slice(&bytes);
-            unsafe {
-                prjfs::sys::PrjWriteFileData(
-                    self.context,
-                    &data.DataStreamId,
-                    rawbuffer,
-                    offset,
-                    length,
-                )
+        let bytes = match self.regops.read_value(&context.file_path) {
+            Some(bytes) => bytes,
+            None => {
+                warn!("<---- get_file_data: file not found");
+                return Err(Error::not_found());
             }
-        } else {
-            winerror::HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND)
         };
 
-        unsafe {
-            prjfs::sys::PrjFreeAlignedBuffer(rawbuffer);
-        }
-        info!("<---- get_file_data: return {:08x}", hr);
-        Ok(hr)
+        let start = (offset as usize).min(bytes.len());
+        let end = start.saturating_add(length as usize).min(bytes.len());
+
+        info!("<---- get_file_data: return {} bytes", end - start);
+        Ok(bytes[start..end].to_vec())
     }
 
     fn notify(
         &self,
-        data: &PRJ_CALLBACK_DATA,
+        context: &CallbackContext,
         _is_directory: bool,
-        notification_type: prjfs::sys::PRJ_NOTIFICATION,
-        destination_file_name: PCWSTR,
-        _parameters: &PRJ_NOTIFICATION_PARAMETERS,
-    ) -> Result<HRESULT> {
-        let filepath = data.FilePathName.to_os();
-        let process = data.TriggeringProcessImageFileName.to_os();
+        notification: Notification,
+    ) -> Verdict {
+        let filepath = &context.file_path;
         info!(
             "---> notify: Path [{:?}] triggered by [{:?}]",
-            filepath, process
+            filepath, context.triggering_process_image
         );
-        info!("--- Notification: 0x{:08x}", notification_type);
+        info!("--- Notification: {:?}", notification);
 
-        match notification_type {
-            prjfs::sys::PRJ_NOTIFICATION_FILE_OPENED => Ok(S_OK),
-            prjfs::sys::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED
-            | prjfs::sys::PRJ_NOTIFICATION_FILE_OVERWRITTEN => {
+        match notification {
+            Notification::FileOpened => Verdict::Allow,
+            Notification::HandleClosed {
+                modified: true,
+                deleted: false,
+            }
+            | Notification::FileOverwritten => {
                 info!(" ----- [{:?}] was modified", filepath);
-                Ok(S_OK)
+                Verdict::Allow
             }
-            prjfs::sys::PRJ_NOTIFY_NEW_FILE_CREATED => {
+            Notification::NewFileCreated => {
                 info!(" ----- [{:?}] was created", filepath);
-                Ok(S_OK)
+                Verdict::Allow
             }
-            prjfs::sys::PRJ_NOTIFY_FILE_RENAMED => {
-                info!(
-                    " ----- [{:?}] -> [{:?}]",
-                    filepath,
-                    destination_file_name.to_os()
-                );
-                Ok(S_OK)
+            Notification::FileRenamed { destination } => {
+                info!(" ----- [{:?}] -> [{:?}]", filepath, destination);
+                Verdict::Allow
             }
-            prjfs::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED => {
+            Notification::HandleClosed { deleted: true, .. } => {
                 info!(" ----- [{:?}] was deleted", filepath);
-                Ok(S_OK)
+                Verdict::Allow
             }
-            prjfs::sys::PRJ_NOTIFICATION_PRE_RENAME => {
+            Notification::PreRename { .. } => {
                 if self.readonly {
                     info!(" ----- rename request for [{:?}] was rejected", filepath);
-                    Ok(HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED))
+                    Verdict::Deny(Error::access_denied())
                 } else {
                     info!(" ----- rename request for [{:?}]", filepath);
-                    Ok(S_OK)
+                    Verdict::Allow
                 }
             }
-            prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE => {
+            Notification::PreDelete => {
                 if self.readonly {
                     info!(" ----- delete request for [{:?}] was rejected", filepath);
-                    Ok(HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED))
+                    Verdict::Deny(Error::access_denied())
                 } else {
                     info!(" ----- delete request for [{:?}]", filepath);
-                    Ok(S_OK)
+                    Verdict::Allow
                 }
             }
-            prjfs::sys::PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL => Ok(S_OK),
-            t => {
-                warn!("notify: Unexpected notification: 0x{:08x}", t);
-                Ok(S_OK)
+            Notification::PreConvertToFull => Verdict::Allow,
+            n => {
+                warn!("notify: Unexpected notification: {:?}", n);
+                Verdict::Allow
             }
         }
     }
 
-    fn query_file_name(&self, _data: &PRJ_CALLBACK_DATA) -> Result<HRESULT> {
-        Ok(S_OK)
-    }
-
-    fn cancel_command(&self, _data: &PRJ_CALLBACK_DATA) -> Result<()> {
+    fn query_file_name(&self, _context: &CallbackContext) -> Result<()> {
         Ok(())
     }
+
+    fn cancel_command(&self, _context: &CallbackContext) {}
 }
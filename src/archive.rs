@@ -0,0 +1,764 @@
+//! A provider that serves the contents of a tar or zip archive.
+//!
+//! `ArchiveProvider` reads the archive once when it is opened and answers
+//! enumerations and placeholder requests from that index. File data is read
+//! lazily: entries stored without compression, every entry of a `.tar` and
+//! stored entries of a `.zip`, are read straight from the archive at the
+//! requested offset. Compressed data cannot be read at an offset, so the
+//! first request decompresses it into a cache directory — the whole tar of a
+//! `.tar.gz`, or the single entry of a `.zip` — and later requests read from
+//! there. The cache is removed when the provider is dropped.
+//!
+//! Only available with the `archive` feature.
+
+use flate2::read::GzDecoder;
+use log::warn;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::collation::FileNameKey;
+use crate::enumeration::{DirEntry, DirectorySource, SessionTable};
+use crate::error::{Error, Result};
+use crate::guid::Guid;
+use crate::provider::{
+    to_filetime, CallbackContext, FileBasicInfo, PlaceholderInfo, SourceProvider,
+};
+
+/// The archive formats `ArchiveProvider` reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guesses the format from the extension: `.tar`, `.tar.gz`, `.tgz` or
+    /// `.zip`, in any case.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Where the bytes of a file entry are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    /// Stored as-is at this offset of the archive, or of the decompressed
+    /// tar for `ArchiveFormat::TarGz`.
+    Offset(u64),
+    /// Compressed; the number of the entry in the archive.
+    Extract(usize),
+}
+
+#[derive(Debug)]
+struct IndexEntry {
+    name: OsString,
+    info: FileBasicInfo,
+    location: Option<Location>,
+    /// The last key component of each child, for directories.
+    children: Vec<FileNameKey>,
+}
+
+/// The entries of an archive keyed by their path, compared like ProjFS
+/// compares names. The root has the empty key.
+#[derive(Debug)]
+struct Index {
+    entries: HashMap<Vec<FileNameKey>, IndexEntry>,
+}
+
+impl Index {
+    fn new() -> Self {
+        let root = IndexEntry {
+            name: OsString::new(),
+            info: FileBasicInfo::directory(),
+            location: None,
+            children: Vec::new(),
+        };
+        Index {
+            entries: vec![(Vec::new(), root)].into_iter().collect(),
+        }
+    }
+
+    /// Adds the entry named `path` in the archive, creating the directories
+    /// above it. A later entry with the same path replaces an earlier one.
+    fn insert(&mut self, path: &str, info: FileBasicInfo, location: Option<Location>) {
+        let mut names = Vec::new();
+        for name in path.split(['/', '\\'].as_ref()) {
+            match name {
+                "" | "." => {}
+                ".." => {
+                    warn!("archive: skipping {:?}, which leaves the archive", path);
+                    return;
+                }
+                name => names.push(name),
+            }
+        }
+        let (last, parents) = match names.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+
+        let mut key = Vec::new();
+        for name in parents {
+            self.insert_child(&key, name, FileBasicInfo::directory(), None, false);
+            key.push(FileNameKey::new(name));
+            if !self.entries[&key].info.is_directory {
+                warn!("archive: skipping {:?}, which is below a file", path);
+                return;
+            }
+        }
+        self.insert_child(&key, last, info, location, true);
+    }
+
+    fn insert_child(
+        &mut self,
+        parent: &[FileNameKey],
+        name: &str,
+        info: FileBasicInfo,
+        location: Option<Location>,
+        replace: bool,
+    ) {
+        let mut key = parent.to_vec();
+        key.push(FileNameKey::new(name));
+
+        if let Some(existing) = self.entries.get_mut(&key) {
+            // a directory never replaces a file
+            if !replace || (info.is_directory && !existing.info.is_directory) {
+                return;
+            }
+            existing.info = info;
+            existing.location = location;
+            // a file replacing a directory drops everything below it
+            if !info.is_directory {
+                let children = std::mem::take(&mut existing.children);
+                self.remove_children(&mut key, children);
+            }
+            return;
+        }
+
+        self.entries.insert(
+            key.clone(),
+            IndexEntry {
+                name: name.into(),
+                info,
+                location,
+                children: Vec::new(),
+            },
+        );
+        if let Some(parent) = self.entries.get_mut(parent) {
+            parent.children.push(key.pop().unwrap());
+        }
+    }
+
+    fn remove_children(&mut self, key: &mut Vec<FileNameKey>, children: Vec<FileNameKey>) {
+        for child in children {
+            key.push(child);
+            if let Some(entry) = self.entries.remove(key) {
+                self.remove_children(key, entry.children);
+            }
+            key.pop();
+        }
+    }
+
+    fn get(&self, path: &Path) -> Option<&IndexEntry> {
+        self.entries.get(&key(path)?)
+    }
+}
+
+/// Builds the index key of a path relative to the virtualization root.
+fn key(path: &Path) -> Option<Vec<FileNameKey>> {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(name) => Some(FileNameKey::new(name)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn corrupt<E: fmt::Display>(path: &Path, error: E) -> Error {
+    Error::other(format!("unable to read archive {:?}: {}", path, error))
+}
+
+fn index_tar<R: Read>(path: &Path, reader: R, index: &mut Index) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(|e| corrupt(path, e))? {
+        let entry = entry.map_err(|e| corrupt(path, e))?;
+        let name = entry.path().map_err(|e| corrupt(path, e))?;
+        let name = name.to_string_lossy().into_owned();
+        let header = entry.header();
+        let kind = header.entry_type();
+
+        let mut info = if kind.is_dir() {
+            FileBasicInfo::directory()
+        } else if kind.is_file() || kind.is_contiguous() {
+            FileBasicInfo::file(entry.size())
+        } else {
+            if kind.is_gnu_sparse() {
+                warn!("archive: skipping sparse file {:?}", name);
+            }
+            continue;
+        };
+        if let Ok(mtime) = header.mtime() {
+            info.last_write_time = to_filetime(UNIX_EPOCH + Duration::from_secs(mtime));
+            info.change_time = info.last_write_time;
+        }
+
+        let location = if info.is_directory {
+            None
+        } else {
+            Some(Location::Offset(entry.raw_file_position()))
+        };
+        index.insert(&name, info, location);
+    }
+    Ok(())
+}
+
+fn index_zip(path: &Path, file: File, index: &mut Index) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| corrupt(path, e))?;
+    for number in 0..archive.len() {
+        let entry = archive.by_index_raw(number).map_err(|e| corrupt(path, e))?;
+
+        let mut info = if entry.is_dir() {
+            FileBasicInfo::directory()
+        } else {
+            FileBasicInfo::file(entry.size())
+        };
+        if let Some(time) = entry.last_modified() {
+            info.last_write_time = zip_filetime(&time);
+            info.change_time = info.last_write_time;
+        }
+
+        let location = match entry.data_start() {
+            _ if entry.is_dir() => None,
+            Some(start) if entry.compression() == zip::CompressionMethod::Stored => {
+                Some(Location::Offset(start))
+            }
+            _ => Some(Location::Extract(number)),
+        };
+        index.insert(entry.name(), info, location);
+    }
+    Ok(())
+}
+
+/// Converts the local time of a zip entry to a `FILETIME`, taking it as UTC.
+fn zip_filetime(time: &zip::DateTime) -> i64 {
+    // days since 1970-01-01 of a proleptic Gregorian date
+    let (month, day) = (i64::from(time.month()), i64::from(time.day()));
+    let year = i64::from(time.year()) - if month <= 2 { 1 } else { 0 };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86_400
+        + i64::from(time.hour()) * 3_600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    to_filetime(UNIX_EPOCH) + seconds * 10_000_000
+}
+
+/// Serves the virtualization root from a tar or zip archive.
+#[derive(Debug)]
+pub struct ArchiveProvider {
+    path: PathBuf,
+    format: ArchiveFormat,
+    index: Index,
+    sessions: SessionTable,
+    cache_dir: PathBuf,
+    owns_cache_dir: bool,
+    /// The files decompressed into `cache_dir` by name, each `true` once
+    /// written. The first read of a file gunzips the archive or inflates the
+    /// zip entry while holding that file's slot: reads of the same file wait
+    /// for that one copy, and reads backed by other files go on meanwhile.
+    cached: Mutex<HashMap<String, Arc<Mutex<bool>>>>,
+    /// Counts the cache files written, to check that none is written twice.
+    #[cfg(test)]
+    fills: std::sync::atomic::AtomicUsize,
+}
+
+impl ArchiveProvider {
+    /// Opens and indexes the archive at `path`, guessing its format from the
+    /// extension.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        match ArchiveFormat::from_path(&path) {
+            Some(format) => Self::open_as(path, format),
+            None => Err(Error::invalid_argument()
+                .with_message(format!("{:?} is not a .tar, .tar.gz or .zip file", path))),
+        }
+    }
+
+    /// Opens and indexes the archive at `path` as `format`.
+    pub fn open_as<P: Into<PathBuf>>(path: P, format: ArchiveFormat) -> Result<Self> {
+        let path = path.into();
+        let file = File::open(&path)?;
+
+        let mut index = Index::new();
+        match format {
+            ArchiveFormat::Tar => index_tar(&path, file, &mut index)?,
+            ArchiveFormat::TarGz => index_tar(&path, GzDecoder::new(file), &mut index)?,
+            ArchiveFormat::Zip => index_zip(&path, file, &mut index)?,
+        }
+
+        Ok(ArchiveProvider {
+            path,
+            format,
+            index,
+            sessions: SessionTable::new(),
+            cache_dir: std::env::temp_dir().join(format!("prjfs-archive-{}", Guid::new_v4()?)),
+            owns_cache_dir: true,
+            cached: Default::default(),
+            #[cfg(test)]
+            fills: Default::default(),
+        })
+    }
+
+    /// Decompresses into `dir` instead of a new directory under the
+    /// temporary directory. Only the files written there are removed.
+    pub fn cache_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.cache_dir = dir.into();
+        self.owns_cache_dir = false;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    /// Returns the path of the cache file `name`, calling `fill` to write it
+    /// the first time.
+    fn cached<F>(&self, name: &str, fill: F) -> Result<PathBuf>
+    where
+        F: FnOnce(&mut File) -> Result<()>,
+    {
+        let path = self.cache_dir.join(name);
+        let slot = Arc::clone(
+            self.cached
+                .lock()
+                .unwrap()
+                .entry(name.to_string())
+                .or_default(),
+        );
+        let mut written = slot.lock().unwrap();
+        if *written {
+            return Ok(path);
+        }
+
+        #[cfg(test)]
+        self.fills.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        fs::create_dir_all(&self.cache_dir)?;
+        let result = File::create(&path)
+            .map_err(Error::from)
+            .and_then(|mut file| fill(&mut file));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        *written = true;
+        Ok(path)
+    }
+
+    fn extract_zip_entry(&self, number: usize, dest: &mut File) -> Result<()> {
+        let mut archive =
+            zip::ZipArchive::new(File::open(&self.path)?).map_err(|e| corrupt(&self.path, e))?;
+        let mut entry = archive
+            .by_index(number)
+            .map_err(|e| corrupt(&self.path, e))?;
+        io::copy(&mut entry, dest)?;
+        Ok(())
+    }
+}
+
+impl Drop for ArchiveProvider {
+    fn drop(&mut self) {
+        for (name, written) in self.cached.get_mut().unwrap().drain() {
+            if *written.lock().unwrap() {
+                let _ = fs::remove_file(self.cache_dir.join(name));
+            }
+        }
+        if self.owns_cache_dir {
+            let _ = fs::remove_dir(&self.cache_dir);
+        }
+    }
+}
+
+impl DirectorySource for ArchiveProvider {
+    fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        let parent = key(path).ok_or_else(Error::not_found)?;
+        let entry = self
+            .index
+            .entries
+            .get(&parent)
+            .ok_or_else(Error::not_found)?;
+        if !entry.info.is_directory {
+            return Err(Error::not_a_directory());
+        }
+
+        let mut key = parent;
+        let entries = entry
+            .children
+            .iter()
+            .map(|child| {
+                key.push(child.clone());
+                let child = &self.index.entries[&key];
+                key.pop();
+                DirEntry {
+                    name: child.name.clone(),
+                    info: child.info,
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+}
+
+impl SourceProvider for ArchiveProvider {
+    fn sessions(&self) -> &SessionTable {
+        &self.sessions
+    }
+
+    fn placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo> {
+        match self.index.get(&context.file_path) {
+            Some(entry) => Ok(entry.info.into()),
+            None => Err(Error::not_found()),
+        }
+    }
+
+    fn file_data(&self, context: &CallbackContext, offset: u64, length: u32) -> Result<Vec<u8>> {
+        let entry = self
+            .index
+            .get(&context.file_path)
+            .ok_or_else(Error::not_found)?;
+        let (path, start) = match entry.location {
+            None => return Err(Error::invalid_argument()),
+            Some(Location::Offset(start)) if self.format == ArchiveFormat::TarGz => {
+                let path = self.cached("archive.tar", |dest| {
+                    io::copy(&mut GzDecoder::new(File::open(&self.path)?), dest)?;
+                    Ok(())
+                })?;
+                (path, start)
+            }
+            Some(Location::Offset(start)) => (self.path.clone(), start),
+            Some(Location::Extract(number)) => {
+                let path = self.cached(&format!("{}.bin", number), |dest| {
+                    self.extract_zip_entry(number, dest)
+                })?;
+                (path, 0)
+            }
+        };
+
+        // never read past the entry into whatever follows it
+        let length = u64::from(length).min(entry.info.file_size.saturating_sub(offset));
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(start + offset))?;
+        let mut bytes = Vec::with_capacity(length as usize);
+        file.take(length).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn lookup(&self, context: &CallbackContext) -> Result<()> {
+        match self.index.get(&context.file_path) {
+            Some(_) => Ok(()),
+            None => Err(Error::not_found()),
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::testutil::{entry_names, TempDir};
+
+#[cfg(test)]
+fn big_file() -> Vec<u8> {
+    (0..10_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+#[cfg(test)]
+fn write_tar<W: io::Write>(writer: W) -> W {
+    let mut builder = tar::Builder::new(writer);
+    let mut append = |path: &str, kind: tar::EntryType, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_000_000_000);
+        header.set_cksum();
+        builder.append_data(&mut header, path, data).unwrap();
+    };
+    append("empty/", tar::EntryType::Directory, b"");
+    append("docs/a.md", tar::EntryType::Regular, b"# a");
+    append("./docs/B.bin", tar::EntryType::Regular, &big_file());
+    append("readme.txt", tar::EntryType::Regular, b"hello, archive");
+    builder.into_inner().unwrap()
+}
+
+#[cfg(test)]
+fn write_archives() -> TempDir {
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    let dir = TempDir::new("archives");
+
+    write_tar(File::create(dir.join("test.tar")).unwrap());
+    let gz = flate2::write::GzEncoder::new(
+        File::create(dir.join("test.tar.gz")).unwrap(),
+        flate2::Compression::default(),
+    );
+    write_tar(gz).finish().unwrap();
+
+    let mut zip = zip::ZipWriter::new(File::create(dir.join("test.zip")).unwrap());
+    let stored = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored)
+        .last_modified_time(zip::DateTime::from_date_and_time(2001, 9, 9, 1, 46, 40).unwrap());
+    let deflated = stored.compression_method(zip::CompressionMethod::Deflated);
+    zip.add_directory("empty/", stored).unwrap();
+    zip.start_file("docs/a.md", stored).unwrap();
+    zip.write_all(b"# a").unwrap();
+    zip.start_file("docs/B.bin", deflated).unwrap();
+    zip.write_all(&big_file()).unwrap();
+    zip.start_file("readme.txt", stored).unwrap();
+    zip.write_all(b"hello, archive").unwrap();
+    zip.finish().unwrap();
+
+    dir
+}
+
+#[test]
+fn test_format_from_path() {
+    let cases = [
+        ("a.tar", Some(ArchiveFormat::Tar)),
+        ("a.TAR.GZ", Some(ArchiveFormat::TarGz)),
+        ("a.tgz", Some(ArchiveFormat::TarGz)),
+        ("dir/a.zip", Some(ArchiveFormat::Zip)),
+        ("a.gz", None),
+        ("zip", None),
+    ];
+    for (path, format) in cases.iter() {
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new(path)),
+            *format,
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        ArchiveProvider::open("a.rar").unwrap_err().hresult(),
+        Error::invalid_argument().hresult()
+    );
+}
+
+#[test]
+fn test_serve_archives() {
+    use crate::sim::SimHost;
+
+    let dir = write_archives();
+    // (archive, files the first reads decompress into the cache)
+    let cases = [
+        ("test.tar", vec![]),
+        ("test.tar.gz", vec!["archive.tar"]),
+        ("test.zip", vec!["2.bin"]),
+    ];
+
+    for (name, cached) in cases.iter() {
+        let cache_dir = dir.join(format!("{}-cache", name));
+        let provider = ArchiveProvider::open(dir.join(name))
+            .unwrap()
+            .cache_dir(&cache_dir);
+        let host = SimHost::new(provider).chunk_size(4096).entries_per_call(2);
+
+        let names = |entries| entry_names(entries, |info| info.file_size);
+        assert_eq!(
+            names(host.read_dir("", None).unwrap()),
+            [
+                ("docs".to_string(), 0),
+                ("empty".to_string(), 0),
+                ("readme.txt".to_string(), 14),
+            ],
+            "{}",
+            name
+        );
+        assert_eq!(
+            names(host.read_dir("DOCS", None).unwrap()),
+            [("a.md".to_string(), 3), ("B.bin".to_string(), 10_000)]
+        );
+        assert!(host.read_dir("empty", None).unwrap().is_empty());
+        assert_eq!(
+            host.read_dir("missing", None).unwrap_err(),
+            Error::not_found()
+        );
+
+        let info = host.placeholder_info("readme.txt").unwrap().basic_info;
+        assert_eq!(info.file_size, 14);
+        assert_eq!(
+            info.last_write_time,
+            to_filetime(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+        );
+        assert!(
+            host.placeholder_info("docs")
+                .unwrap()
+                .basic_info
+                .is_directory
+        );
+
+        // stored data is read in place
+        assert_eq!(host.read_file("readme.txt").unwrap(), b"hello, archive");
+        assert_eq!(host.read_file("docs/a.md").unwrap(), b"# a");
+        let listed = |dir: &Path| -> Vec<String> {
+            let mut files: Vec<String> = fs::read_dir(dir)
+                .map(|entries| {
+                    entries
+                        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                        .collect()
+                })
+                .unwrap_or_default();
+            files.sort();
+            files
+        };
+        if *name != "test.tar.gz" {
+            assert!(listed(&cache_dir).is_empty(), "{}", name);
+        }
+
+        assert_eq!(host.read_file("docs/b.bin").unwrap(), big_file());
+        assert_eq!(listed(&cache_dir), *cached, "{}", name);
+        assert!(host.read_file("docs").is_err());
+
+        drop(host);
+        assert!(listed(&cache_dir).is_empty(), "{}", name);
+    }
+}
+
+#[test]
+fn test_default_cache_dir_is_removed() {
+    let dir = write_archives();
+    let provider = ArchiveProvider::open(dir.join("test.zip")).unwrap();
+    let cache_dir = provider.cache_dir.clone();
+    assert_eq!(provider.format(), ArchiveFormat::Zip);
+
+    let host = crate::sim::SimHost::new(provider);
+    assert_eq!(host.read_file("docs/B.bin").unwrap(), big_file());
+    assert!(cache_dir.join("2.bin").is_file());
+    drop(host);
+    assert!(!cache_dir.exists());
+}
+
+#[test]
+fn test_cache_files_written_once() {
+    use crate::provider::CallbackFlags;
+    use crate::testutil::test_context;
+    use std::sync::atomic::Ordering;
+    use std::sync::{mpsc, Barrier};
+
+    let dir = write_archives();
+    let read_big_file = |provider: &ArchiveProvider| {
+        let context = test_context("docs/B.bin", CallbackFlags::empty());
+        provider.file_data(&context, 0, 10_000)
+    };
+
+    // concurrent first reads of a .tar.gz entry gunzip the archive only once
+    let provider = Arc::new(ArchiveProvider::open(dir.join("test.tar.gz")).unwrap());
+    let barrier = Arc::new(Barrier::new(8));
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let (provider, barrier) = (provider.clone(), barrier.clone());
+            std::thread::spawn(move || {
+                barrier.wait();
+                read_big_file(&provider)
+            })
+        })
+        .collect();
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), Ok(big_file()));
+    }
+    assert_eq!(provider.fills.load(Ordering::SeqCst), 1);
+
+    // a zip entry is inflated while another cache file is still being written
+    let provider = Arc::new(ArchiveProvider::open(dir.join("test.zip")).unwrap());
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let writer = {
+        let provider = provider.clone();
+        std::thread::spawn(move || {
+            provider
+                .cached("slow.bin", |_| {
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    Ok(())
+                })
+                .map(|_| ())
+        })
+    };
+    started_rx.recv().unwrap();
+    assert_eq!(read_big_file(&provider), Ok(big_file()));
+    release_tx.send(()).unwrap();
+    assert_eq!(writer.join().unwrap(), Ok(()));
+    assert_eq!(provider.fills.load(Ordering::SeqCst), 2);
+
+    // a failed write is cleaned up and retried by the next read
+    let failed = provider.cache_dir.join("failed.bin");
+    let error = provider.cached("failed.bin", |_| Err(Error::other("disk full")));
+    assert!(error.is_err());
+    assert!(!failed.exists());
+    assert_eq!(provider.cached("failed.bin", |_| Ok(())), Ok(failed));
+}
+
+#[test]
+fn test_index_paths() {
+    let mut index = Index::new();
+    index.insert(
+        "a/b/c.txt",
+        FileBasicInfo::file(1),
+        Some(Location::Offset(0)),
+    );
+    index.insert("A/B/", FileBasicInfo::directory(), None);
+    index.insert(
+        "a\\b\\c.txt",
+        FileBasicInfo::file(2),
+        Some(Location::Offset(9)),
+    );
+    index.insert("../evil", FileBasicInfo::file(1), Some(Location::Offset(0)));
+    index.insert("/", FileBasicInfo::directory(), None);
+
+    let entry = index.get(Path::new("a/b/c.txt")).unwrap();
+    assert_eq!(entry.info, FileBasicInfo::file(2));
+    assert_eq!(entry.location, Some(Location::Offset(9)));
+    assert_eq!(index.get(Path::new("a")).unwrap().children.len(), 1);
+    assert_eq!(index.get(Path::new("a")).unwrap().name, "a");
+    assert!(index.get(Path::new("evil")).is_none());
+    assert_eq!(index.entries.len(), 4);
+
+    // a file replacing a directory removes what was below it, and nothing
+    // is added below a file
+    index.insert("a/b", FileBasicInfo::file(3), Some(Location::Offset(5)));
+    index.insert(
+        "a/b/d.txt",
+        FileBasicInfo::file(1),
+        Some(Location::Offset(0)),
+    );
+    index.insert("a/b/", FileBasicInfo::directory(), None);
+    let entry = index.get(Path::new("a/b")).unwrap();
+    assert_eq!(entry.info, FileBasicInfo::file(3));
+    assert!(entry.children.is_empty());
+    assert!(index.get(Path::new("a/b/c.txt")).is_none());
+    assert!(index.get(Path::new("a/b/d.txt")).is_none());
+    assert_eq!(index.entries.len(), 3);
+}
@@ -0,0 +1,109 @@
+//! File name ordering with the same rules as `PrjFileNameCompare`.
+//!
+//! Names are compared one UTF-16 code unit at a time after mapping each unit
+//! through the upcase table. When one name is a prefix of the other, the
+//! shorter one sorts first. Names that only differ in case compare equal, just
+//! as they refer to the same file in the virtualized namespace; sorting with a
+//! stable sort keeps such entries in the order they were produced.
+
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+
+use crate::unicode::{upcase, with_wide};
+
+/// Compares file names the way ProjFS expects directory entries to be ordered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileNameOrd;
+
+impl FileNameOrd {
+    pub fn compare<A: AsRef<OsStr>, B: AsRef<OsStr>>(&self, a: A, b: B) -> Ordering {
+        with_wide(a.as_ref(), |a| {
+            with_wide(b.as_ref(), |b| a.map(upcase).cmp(b.map(upcase)))
+        })
+    }
+
+    /// Compares names given as UTF-16 code units.
+    pub fn compare_wide(&self, a: &[u16], b: &[u16]) -> Ordering {
+        a.iter()
+            .map(|&unit| upcase(unit))
+            .cmp(b.iter().map(|&unit| upcase(unit)))
+    }
+
+    /// Sorts `items` by the file name `name` returns for each of them. The sort
+    /// is stable and computes each sort key only once.
+    pub fn sort_by_name<T, F, S>(&self, items: &mut [T], mut name: F)
+    where
+        F: FnMut(&T) -> S,
+        S: AsRef<OsStr>,
+    {
+        items.sort_by_cached_key(|item| FileNameKey::new(name(item)));
+    }
+}
+
+/// A precomputed sort key for a file name, ordered like `FileNameOrd`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileNameKey {
+    key: Vec<u16>,
+}
+
+impl FileNameKey {
+    pub fn new<S: AsRef<OsStr>>(name: S) -> Self {
+        FileNameKey {
+            key: with_wide(name.as_ref(), |units| units.map(upcase).collect()),
+        }
+    }
+
+    /// Builds the key of a name given as UTF-16 code units.
+    pub fn from_wide(name: &[u16]) -> Self {
+        FileNameKey {
+            key: name.iter().map(|&unit| upcase(unit)).collect(),
+        }
+    }
+}
+
+/// Compares two file names, like `PrjFileNameCompare`.
+pub fn compare<A: AsRef<OsStr>, B: AsRef<OsStr>>(a: A, b: B) -> Ordering {
+    FileNameOrd.compare(a, b)
+}
+
+#[test]
+fn test_compare() {
+    let cases: &[(&str, &str, Ordering)] = &[
+        ("a", "a", Ordering::Equal),
+        ("a", "A", Ordering::Equal),
+        ("abc", "ABC", Ordering::Equal),
+        ("a", "B", Ordering::Less),
+        ("B", "a", Ordering::Greater),
+        ("ab", "abc", Ordering::Less),
+        ("ABC", "ab", Ordering::Greater),
+        ("", "a", Ordering::Less),
+        // letters are compared in upper case, so they sort before `_`
+        ("_", "a", Ordering::Greater),
+        ("a_b", "a.b", Ordering::Greater),
+        ("äb", "ÄB", Ordering::Equal),
+        ("z", "ä", Ordering::Less),
+        ("σ", "Σ", Ordering::Equal),
+        ("ß", "SS", Ordering::Greater),
+        // code units outside the BMP are compared as surrogates
+        ("😀", "\u{FFFD}", Ordering::Less),
+    ];
+
+    for (a, b, expected) in cases {
+        assert_eq!(compare(a, b), *expected, "comparing {:?} with {:?}", a, b);
+        assert_eq!(
+            FileNameKey::new(a).cmp(&FileNameKey::new(b)),
+            *expected,
+            "comparing keys of {:?} and {:?}",
+            a,
+            b
+        );
+    }
+}
+
+#[test]
+fn test_sort_by_name() {
+    let mut names = vec!["b.txt", "A.txt", "_x", "a", "B", "a.TXT", "aa"];
+    FileNameOrd.sort_by_name(&mut names, |name| *name);
+
+    assert_eq!(names, ["a", "A.txt", "a.TXT", "aa", "B", "b.txt", "_x"]);
+}
@@ -1,7 +1,7 @@
 use std::ffi::{OsStr, OsString};
-use std::iter::once;
-use std::os::windows::ffi::{OsStrExt, OsStringExt};
-use winapi::um::{winbase::lstrlenW, winnt::PCWSTR};
+
+use crate::sys::PCWSTR;
+use crate::unicode::encode_wide;
 
 pub struct WStr {
     data: Vec<u16>,
@@ -22,11 +22,8 @@ where
     T: AsRef<OsStr>,
 {
     fn to_wstr(&self) -> WStr {
-        let data = self
-            .as_ref()
-            .encode_wide()
-            .chain(once(0))
-            .collect::<Vec<u16>>();
+        let mut data = encode_wide(self.as_ref());
+        data.push(0);
 
         WStr { data }
     }
@@ -38,8 +35,22 @@ pub trait RawWStrExt {
 
 impl RawWStrExt for PCWSTR {
     fn to_os(&self) -> OsString {
-        let length = unsafe { lstrlenW(*self) as usize };
+        let mut length = 0;
+        while unsafe { *self.add(length) } != 0 {
+            length += 1;
+        }
         let wstr = unsafe { std::slice::from_raw_parts(*self, length) };
-        OsString::from_wide(wstr)
+        decode_wide(wstr)
     }
 }
+
+#[cfg(windows)]
+fn decode_wide(wstr: &[u16]) -> OsString {
+    use std::os::windows::ffi::OsStringExt;
+    OsString::from_wide(wstr)
+}
+
+#[cfg(not(windows))]
+fn decode_wide(wstr: &[u16]) -> OsString {
+    String::from_utf16_lossy(wstr).into()
+}
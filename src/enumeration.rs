@@ -0,0 +1,446 @@
+//! Directory enumeration sessions.
+//!
+//! ProjFS enumerates a directory with a `start_dir_enum` call, any number of
+//! `get_dir_enum` calls and a final `end_dir_enum` call, all sharing the same
+//! enumeration ID. `SessionTable` keeps the per-session state across these
+//! calls so a provider only has to list the entries of a directory through
+//! `DirectorySource`.
+//!
+//! The search expression of a session is the one given to the first
+//! `get_dir_enum` call, or to the first call after a restart scan. Expressions
+//! passed to later calls are ignored, as ProjFS requires.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::collation::FileNameOrd;
+use crate::error::{Error, Result};
+use crate::guid::Guid;
+use crate::pattern::Pattern;
+use crate::provider::{CallbackContext, CallbackFlags, DirEntryBuffer, FileBasicInfo};
+
+/// An entry of a virtualized directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub info: FileBasicInfo,
+}
+
+impl DirEntry {
+    pub fn directory<S: Into<OsString>>(name: S) -> Self {
+        DirEntry {
+            name: name.into(),
+            info: FileBasicInfo::directory(),
+        }
+    }
+
+    pub fn file<S: Into<OsString>>(name: S, size: u64) -> Self {
+        DirEntry {
+            name: name.into(),
+            info: FileBasicInfo::file(size),
+        }
+    }
+}
+
+/// Lists the contents of virtualized directories.
+pub trait DirectorySource {
+    /// Returns the entries of the directory at `path`, relative to the
+    /// virtualization root. The entries may be in any order.
+    fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>>;
+}
+
+impl<F> DirectorySource for F
+where
+    F: Fn(&Path) -> Result<Vec<DirEntry>>,
+{
+    fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        self(path)
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    path: PathBuf,
+    /// Captured on the first `get_dir_enum` call, `None` before that.
+    pattern: Option<Pattern>,
+    entries: Option<Vec<DirEntry>>,
+    index: usize,
+}
+
+impl Session {
+    fn new(path: PathBuf) -> Self {
+        Session {
+            path,
+            pattern: None,
+            entries: None,
+            index: 0,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.pattern = None;
+        self.entries = None;
+        self.index = 0;
+    }
+}
+
+/// Tracks the directory enumerations in progress, keyed by enumeration ID.
+///
+/// Each session has its own lock, so a slow listing only holds up the
+/// enumeration it belongs to.
+#[derive(Debug, Default)]
+pub struct SessionTable {
+    sessions: Mutex<HashMap<Guid, Arc<Mutex<Session>>>>,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Begins an enumeration of `path`, to be called from
+    /// `ProviderT::start_dir_enum`.
+    pub fn start(&self, enumeration_id: Guid, path: &Path) {
+        self.sessions.lock().unwrap().insert(
+            enumeration_id,
+            Arc::new(Mutex::new(Session::new(path.to_owned()))),
+        );
+    }
+
+    /// Ends an enumeration, to be called from `ProviderT::end_dir_enum`.
+    /// Returns `false` if the enumeration was not known.
+    pub fn end(&self, enumeration_id: Guid) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&enumeration_id)
+            .is_some()
+    }
+
+    /// Returns the number of enumerations in progress.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Services `ProviderT::get_dir_enum`. The first call of a session (or the
+    /// first call after a restart) captures `search_expression`; until the
+    /// entries are loaded, calls list the directory through `source`, then
+    /// filter them with the captured expression and sort them. Every call
+    /// fills `buffer` with as many entries as it takes, resuming where the
+    /// previous call stopped.
+    pub fn fill<S: DirectorySource + ?Sized>(
+        &self,
+        source: &S,
+        context: &CallbackContext,
+        enumeration_id: Guid,
+        search_expression: Option<&OsStr>,
+        buffer: &mut dyn DirEntryBuffer,
+    ) -> Result<()> {
+        let session = match self.sessions.lock().unwrap().get(&enumeration_id) {
+            Some(session) => Arc::clone(session),
+            None => return Err(Error::invalid_argument()),
+        };
+        let mut session = session.lock().unwrap();
+        let session = &mut *session;
+
+        if context.flags.contains(CallbackFlags::ENUM_RESTART_SCAN) {
+            session.restart();
+        }
+
+        let pattern = session
+            .pattern
+            .get_or_insert_with(|| search_expression.map(Pattern::new).unwrap_or_default());
+
+        if session.entries.is_none() {
+            let mut entries = source.read_dir(&session.path)?;
+            entries.retain(|entry| pattern.matches(&entry.name));
+            FileNameOrd.sort_by_name(&mut entries, |entry| entry.name.clone());
+            session.entries = Some(entries);
+        }
+
+        let single_entry = context
+            .flags
+            .contains(CallbackFlags::ENUM_RETURN_SINGLE_ENTRY);
+        let entries = session.entries.as_ref().unwrap();
+        let mut filled = 0;
+
+        while let Some(entry) = entries.get(session.index) {
+            if !buffer.fill(&entry.name, &entry.info) {
+                if filled == 0 {
+                    return Err(Error::insufficient_buffer());
+                }
+                break;
+            }
+
+            session.index += 1;
+            filled += 1;
+
+            if single_entry {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct VecBuffer {
+    capacity: usize,
+    names: Vec<OsString>,
+}
+
+#[cfg(test)]
+impl VecBuffer {
+    fn new(capacity: usize) -> Self {
+        VecBuffer {
+            capacity,
+            names: Vec::new(),
+        }
+    }
+
+    fn take(&mut self) -> Vec<OsString> {
+        std::mem::take(&mut self.names)
+    }
+}
+
+#[cfg(test)]
+impl DirEntryBuffer for VecBuffer {
+    fn fill(&mut self, name: &OsStr, _info: &FileBasicInfo) -> bool {
+        if self.names.len() == self.capacity {
+            return false;
+        }
+        self.names.push(name.to_owned());
+        true
+    }
+}
+
+#[cfg(test)]
+use crate::testutil::test_context;
+
+#[cfg(test)]
+fn test_source(path: &Path) -> Result<Vec<DirEntry>> {
+    assert_eq!(path, Path::new("dir"));
+    Ok(vec![
+        DirEntry::file("b.txt", 1),
+        DirEntry::directory("C"),
+        DirEntry::file("a.txt", 2),
+        DirEntry::file("d.rs", 3),
+    ])
+}
+
+#[test]
+fn test_fill_resumes_after_full_buffer() {
+    let table = SessionTable::new();
+    let id = Guid::default();
+    let context = test_context("dir", CallbackFlags::empty());
+    let mut buffer = VecBuffer::new(2);
+
+    table.start(id, Path::new("dir"));
+    table
+        .fill(&test_source, &context, id, None, &mut buffer)
+        .unwrap();
+    assert_eq!(buffer.take(), ["a.txt", "b.txt"]);
+
+    table
+        .fill(&test_source, &context, id, None, &mut buffer)
+        .unwrap();
+    assert_eq!(buffer.take(), ["C", "d.rs"]);
+
+    table
+        .fill(&test_source, &context, id, None, &mut buffer)
+        .unwrap();
+    assert!(buffer.take().is_empty());
+
+    assert!(table.end(id));
+    assert!(table.is_empty());
+}
+
+#[test]
+fn test_fill_filters_and_restarts() {
+    let table = SessionTable::new();
+    let id = Guid::default();
+    let mut buffer = VecBuffer::new(10);
+
+    table.start(id, Path::new("dir"));
+    table
+        .fill(
+            &test_source,
+            &test_context("dir", CallbackFlags::ENUM_RETURN_SINGLE_ENTRY),
+            id,
+            Some(OsStr::new("*.TXT")),
+            &mut buffer,
+        )
+        .unwrap();
+    assert_eq!(buffer.take(), ["a.txt"]);
+
+    table
+        .fill(
+            &test_source,
+            &test_context("dir", CallbackFlags::ENUM_RESTART_SCAN),
+            id,
+            Some(OsStr::new("*")),
+            &mut buffer,
+        )
+        .unwrap();
+    assert_eq!(buffer.take(), ["a.txt", "b.txt", "C", "d.rs"]);
+}
+
+#[test]
+fn test_fill_errors() {
+    let table = SessionTable::new();
+    let id = Guid::default();
+    let context = test_context("dir", CallbackFlags::empty());
+
+    // unknown session
+    let err = table
+        .fill(&test_source, &context, id, None, &mut VecBuffer::new(1))
+        .unwrap_err();
+    assert_eq!(err, Error::invalid_argument());
+
+    // not even one entry fits
+    table.start(id, Path::new("dir"));
+    let err = table
+        .fill(&test_source, &context, id, None, &mut VecBuffer::new(0))
+        .unwrap_err();
+    assert_eq!(err, Error::insufficient_buffer());
+    assert!(table.end(id));
+    assert!(!table.end(id));
+}
+
+#[test]
+fn test_search_expression_kept_across_calls() {
+    let table = SessionTable::new();
+    let id = Guid::default();
+    let context = test_context("dir", CallbackFlags::empty());
+    let mut buffer = VecBuffer::new(1);
+
+    table.start(id, Path::new("dir"));
+    let calls: &[(Option<&str>, &[&str])] = &[
+        (Some("*.txt"), &["a.txt"]),
+        (Some("*"), &["b.txt"]),
+        (None, &[]),
+        (Some("d.rs"), &[]),
+    ];
+
+    for (expression, expected) in calls {
+        table
+            .fill(
+                &test_source,
+                &context,
+                id,
+                expression.map(OsStr::new),
+                &mut buffer,
+            )
+            .unwrap();
+        assert_eq!(buffer.take(), *expected, "expression {:?}", expression);
+    }
+}
+
+#[test]
+fn test_search_expression_recaptured_on_restart() {
+    let table = SessionTable::new();
+    let id = Guid::default();
+    let mut buffer = VecBuffer::new(10);
+
+    table.start(id, Path::new("dir"));
+    let calls: &[(CallbackFlags, Option<&str>, &[&str])] = &[
+        (CallbackFlags::empty(), Some("*.rs"), &["d.rs"]),
+        (CallbackFlags::empty(), Some("*.txt"), &[]),
+        (
+            CallbackFlags::ENUM_RESTART_SCAN,
+            Some("*.txt"),
+            &["a.txt", "b.txt"],
+        ),
+        (CallbackFlags::empty(), None, &[]),
+        (
+            CallbackFlags::ENUM_RESTART_SCAN,
+            None,
+            &["a.txt", "b.txt", "C", "d.rs"],
+        ),
+        (CallbackFlags::ENUM_RESTART_SCAN, Some("c"), &["C"]),
+    ];
+
+    for (flags, expression, expected) in calls {
+        table
+            .fill(
+                &test_source,
+                &test_context("dir", *flags),
+                id,
+                expression.map(OsStr::new),
+                &mut buffer,
+            )
+            .unwrap();
+        assert_eq!(buffer.take(), *expected, "expression {:?}", expression);
+    }
+}
+
+#[test]
+fn test_search_expression_captured_when_listing_fails() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let table = SessionTable::new();
+    let id = Guid::default();
+    let context = test_context("dir", CallbackFlags::empty());
+    let mut buffer = VecBuffer::new(10);
+    let failed = AtomicBool::new(false);
+    let flaky_source = |path: &Path| {
+        if failed.swap(true, Ordering::SeqCst) {
+            test_source(path)
+        } else {
+            Err(Error::other("backing store unavailable"))
+        }
+    };
+
+    table.start(id, Path::new("dir"));
+    assert!(table
+        .fill(
+            &flaky_source,
+            &context,
+            id,
+            Some(OsStr::new("*.txt")),
+            &mut buffer
+        )
+        .is_err());
+
+    table
+        .fill(&flaky_source, &context, id, None, &mut buffer)
+        .unwrap();
+    assert_eq!(buffer.take(), ["a.txt", "b.txt"]);
+}
+
+#[test]
+fn test_listing_does_not_block_other_sessions() {
+    let table = SessionTable::new();
+    let (outer, inner) = (
+        Guid::from_fields(1, 0, 0, [0; 8]),
+        Guid::from_fields(2, 0, 0, [0; 8]),
+    );
+    let context = test_context("dir", CallbackFlags::empty());
+
+    // lists another session while this one is being listed, which would
+    // deadlock if the table stayed locked
+    let nested_source = |path: &Path| {
+        let mut buffer = VecBuffer::new(10);
+        table
+            .fill(&test_source, &context, inner, None, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer.take().len(), 4);
+        test_source(path)
+    };
+
+    table.start(outer, Path::new("dir"));
+    table.start(inner, Path::new("dir"));
+    let mut buffer = VecBuffer::new(10);
+    table
+        .fill(&nested_source, &context, outer, None, &mut buffer)
+        .unwrap();
+    assert_eq!(buffer.take().len(), 4);
+    assert_eq!(table.len(), 2);
+}
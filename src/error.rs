@@ -0,0 +1,411 @@
+//! Errors reported by providers.
+//!
+//! ProjFS only understands `HRESULT` status codes, so every `Error` carries
+//! one. The message, if any, is only used for logging.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::root::RootError;
+
+/// A Windows `HRESULT` status code.
+pub type HRESULT = i32;
+
+const E_FAIL: HRESULT = 0x8000_4005_u32 as HRESULT;
+const E_INVALIDARG: HRESULT = 0x8007_0057_u32 as HRESULT;
+const E_OUTOFMEMORY: HRESULT = 0x8007_000E_u32 as HRESULT;
+
+const ERROR_FILE_NOT_FOUND: u32 = 2;
+const ERROR_ACCESS_DENIED: u32 = 5;
+const ERROR_HANDLE_EOF: u32 = 38;
+const ERROR_NOT_SUPPORTED: u32 = 50;
+const ERROR_INSUFFICIENT_BUFFER: u32 = 122;
+const ERROR_DIR_NOT_EMPTY: u32 = 145;
+const ERROR_MOD_NOT_FOUND: u32 = 126;
+const ERROR_ALREADY_EXISTS: u32 = 183;
+const ERROR_DIRECTORY: u32 = 267;
+const ERROR_FILE_SYSTEM_VIRTUALIZATION_UNAVAILABLE: u32 = 369;
+const ERROR_OPERATION_ABORTED: u32 = 995;
+const ERROR_IO_PENDING: u32 = 997;
+const ERROR_ALREADY_INITIALIZED: u32 = 1247;
+const ERROR_TIMEOUT: u32 = 1460;
+const ERROR_NOT_A_REPARSE_POINT: u32 = 4390;
+
+const FACILITY_WIN32: u32 = 7;
+
+/// Converts a Win32 error code into an `HRESULT`, like `HRESULT_FROM_WIN32`.
+pub fn hresult_from_win32(code: u32) -> HRESULT {
+    if code as HRESULT <= 0 {
+        code as HRESULT
+    } else {
+        ((code & 0x0000_FFFF) | (FACILITY_WIN32 << 16) | 0x8000_0000) as HRESULT
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// An error reported back to ProjFS as an `HRESULT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    hresult: HRESULT,
+    message: Option<String>,
+}
+
+impl Error {
+    pub fn from_hresult(hresult: HRESULT) -> Self {
+        Error {
+            hresult,
+            message: None,
+        }
+    }
+
+    pub fn from_win32(code: u32) -> Self {
+        Self::from_hresult(hresult_from_win32(code))
+    }
+
+    /// The file or directory does not exist in the backing store.
+    pub fn not_found() -> Self {
+        Self::from_win32(ERROR_FILE_NOT_FOUND)
+    }
+
+    pub fn access_denied() -> Self {
+        Self::from_win32(ERROR_ACCESS_DENIED)
+    }
+
+    pub fn out_of_memory() -> Self {
+        Self::from_hresult(E_OUTOFMEMORY)
+    }
+
+    pub fn invalid_argument() -> Self {
+        Self::from_hresult(E_INVALIDARG)
+    }
+
+    pub fn already_exists() -> Self {
+        Self::from_win32(ERROR_ALREADY_EXISTS)
+    }
+
+    /// A directory was expected but the path names a file.
+    pub fn not_a_directory() -> Self {
+        Self::from_win32(ERROR_DIRECTORY)
+    }
+
+    pub fn directory_not_empty() -> Self {
+        Self::from_win32(ERROR_DIR_NOT_EMPTY)
+    }
+
+    /// The callback will be completed asynchronously.
+    pub fn io_pending() -> Self {
+        Self::from_win32(ERROR_IO_PENDING)
+    }
+
+    /// Not even one directory entry fits in the buffer ProjFS provided.
+    pub fn insufficient_buffer() -> Self {
+        Self::from_win32(ERROR_INSUFFICIENT_BUFFER)
+    }
+
+    /// An unspecified failure, described by `message`.
+    pub fn other<S: Into<String>>(message: S) -> Self {
+        Self::from_hresult(E_FAIL).with_message(message)
+    }
+
+    /// Attaches a message that is logged along with the `HRESULT`.
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    pub fn hresult(&self) -> HRESULT {
+        self.hresult
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    pub fn is_io_pending(&self) -> bool {
+        self.hresult == hresult_from_win32(ERROR_IO_PENDING)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{} (HRESULT 0x{:08X})", message, self.hresult),
+            None => write!(f, "HRESULT 0x{:08X}", self.hresult),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        let error = match err.raw_os_error() {
+            // raw OS errors are Win32 error codes only on Windows
+            Some(code) if cfg!(windows) => Error::from_win32(code as u32),
+            _ => match err.kind() {
+                io::ErrorKind::NotFound => Error::not_found(),
+                io::ErrorKind::PermissionDenied => Error::access_denied(),
+                io::ErrorKind::AlreadyExists => Error::already_exists(),
+                io::ErrorKind::InvalidInput => Error::invalid_argument(),
+                io::ErrorKind::OutOfMemory => Error::out_of_memory(),
+                io::ErrorKind::Interrupted => Error::from_win32(ERROR_OPERATION_ABORTED),
+                io::ErrorKind::TimedOut => Error::from_win32(ERROR_TIMEOUT),
+                io::ErrorKind::UnexpectedEof => Error::from_win32(ERROR_HANDLE_EOF),
+                io::ErrorKind::Unsupported => Error::from_win32(ERROR_NOT_SUPPORTED),
+                _ => Error::from_hresult(E_FAIL),
+            },
+        };
+
+        error.with_message(err.to_string())
+    }
+}
+
+/// The common reasons for `PrjStartVirtualizing` to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartFailure {
+    /// The root directory is not marked as a virtualization root.
+    NotAPlaceholder,
+    /// The Projected File System optional feature is not enabled.
+    FeatureNotEnabled,
+    /// Another instance is already virtualizing the root.
+    AlreadyVirtualized,
+    /// Any other failure; see the `HRESULT`.
+    Other,
+}
+
+impl StartFailure {
+    /// Decodes the `HRESULT` returned by `PrjStartVirtualizing`.
+    pub fn from_hresult(hresult: HRESULT) -> Self {
+        let is = |code| hresult == hresult_from_win32(code);
+
+        if is(ERROR_NOT_A_REPARSE_POINT) {
+            StartFailure::NotAPlaceholder
+        } else if is(ERROR_FILE_SYSTEM_VIRTUALIZATION_UNAVAILABLE) || is(ERROR_MOD_NOT_FOUND) {
+            StartFailure::FeatureNotEnabled
+        } else if is(ERROR_ALREADY_INITIALIZED) {
+            StartFailure::AlreadyVirtualized
+        } else {
+            StartFailure::Other
+        }
+    }
+}
+
+impl fmt::Display for StartFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            StartFailure::NotAPlaceholder => "the root is not marked as a virtualization root",
+            StartFailure::FeatureNotEnabled => "the ProjFS feature is not enabled",
+            StartFailure::AlreadyVirtualized => "the root is already being virtualized",
+            StartFailure::Other => "virtualization failed to start",
+        };
+        f.write_str(description)
+    }
+}
+
+/// An error returned from `Provider::new`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartError {
+    /// The options given to `Provider::new` are invalid.
+    Options { root: PathBuf, source: Error },
+    /// The virtualization root could not be created, adopted or reopened.
+    Root { root: PathBuf, source: RootError },
+    /// `PrjStartVirtualizing` failed.
+    Virtualize {
+        root: PathBuf,
+        hresult: HRESULT,
+        cause: StartFailure,
+    },
+}
+
+impl StartError {
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn virtualize(root: PathBuf, hresult: HRESULT) -> Self {
+        StartError::Virtualize {
+            root,
+            hresult,
+            cause: StartFailure::from_hresult(hresult),
+        }
+    }
+
+    pub fn root(&self) -> &PathBuf {
+        match self {
+            StartError::Options { root, .. }
+            | StartError::Root { root, .. }
+            | StartError::Virtualize { root, .. } => root,
+        }
+    }
+
+    pub fn hresult(&self) -> HRESULT {
+        match self {
+            StartError::Options { source, .. } => source.hresult(),
+            StartError::Root { source, .. } => source.hresult(),
+            StartError::Virtualize { hresult, .. } => *hresult,
+        }
+    }
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartError::Options { root, source } => {
+                write!(f, "invalid options for {:?}: {}", root, source)
+            }
+            StartError::Root { root, source } => {
+                write!(
+                    f,
+                    "unable to prepare virtualization root {:?}: {}",
+                    root, source
+                )
+            }
+            StartError::Virtualize {
+                root,
+                hresult,
+                cause,
+            } => write!(
+                f,
+                "unable to virtualize {:?}: {} (HRESULT 0x{:08X})",
+                root, cause, hresult
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StartError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StartError::Options { source, .. } => Some(source),
+            StartError::Root { source, .. } => Some(source),
+            StartError::Virtualize { .. } => None,
+        }
+    }
+}
+
+#[test]
+fn test_hresult_from_win32() {
+    assert_eq!(hresult_from_win32(0), 0);
+    assert_eq!(
+        hresult_from_win32(ERROR_FILE_NOT_FOUND),
+        0x8007_0002_u32 as HRESULT
+    );
+    assert_eq!(
+        hresult_from_win32(ERROR_IO_PENDING),
+        0x8007_03E5_u32 as HRESULT
+    );
+    // values that already are HRESULTs pass through
+    assert_eq!(hresult_from_win32(E_FAIL as u32), E_FAIL);
+}
+
+#[test]
+fn test_from_io_error() {
+    let cases = [
+        (io::ErrorKind::NotFound, Error::not_found()),
+        (io::ErrorKind::PermissionDenied, Error::access_denied()),
+        (io::ErrorKind::InvalidInput, Error::invalid_argument()),
+        (io::ErrorKind::OutOfMemory, Error::out_of_memory()),
+        (io::ErrorKind::Other, Error::from_hresult(E_FAIL)),
+    ];
+
+    for (kind, expected) in cases.iter() {
+        let error = Error::from(io::Error::new(*kind, "boom"));
+        assert_eq!(error.hresult(), expected.hresult(), "{:?}", kind);
+        assert_eq!(error.message(), Some("boom"));
+    }
+
+    let error = Error::from(io::Error::from_raw_os_error(ERROR_ACCESS_DENIED as i32));
+    if cfg!(windows) {
+        assert_eq!(error.hresult(), Error::access_denied().hresult());
+    }
+}
+
+#[test]
+fn test_display() {
+    assert_eq!(Error::not_found().to_string(), "HRESULT 0x80070002");
+    assert_eq!(
+        Error::other("registry unavailable").to_string(),
+        "registry unavailable (HRESULT 0x80004005)"
+    );
+    assert!(Error::io_pending().is_io_pending());
+    assert!(!Error::not_found().is_io_pending());
+}
+
+#[test]
+fn test_start_failure_from_hresult() {
+    let cases = [
+        (ERROR_NOT_A_REPARSE_POINT, StartFailure::NotAPlaceholder),
+        (
+            ERROR_FILE_SYSTEM_VIRTUALIZATION_UNAVAILABLE,
+            StartFailure::FeatureNotEnabled,
+        ),
+        (ERROR_MOD_NOT_FOUND, StartFailure::FeatureNotEnabled),
+        (ERROR_ALREADY_INITIALIZED, StartFailure::AlreadyVirtualized),
+        (ERROR_ACCESS_DENIED, StartFailure::Other),
+    ];
+
+    for (code, expected) in cases.iter() {
+        assert_eq!(
+            StartFailure::from_hresult(hresult_from_win32(*code)),
+            *expected,
+            "error {}",
+            code
+        );
+    }
+    assert_eq!(StartFailure::from_hresult(E_FAIL), StartFailure::Other);
+    // the bare Win32 code is not an HRESULT
+    assert_eq!(
+        StartFailure::from_hresult(ERROR_ALREADY_INITIALIZED as HRESULT),
+        StartFailure::Other
+    );
+}
+
+#[test]
+fn test_start_error() {
+    let error = StartError::virtualize(
+        PathBuf::from("root"),
+        hresult_from_win32(ERROR_NOT_A_REPARSE_POINT),
+    );
+    assert_eq!(error.root(), &PathBuf::from("root"));
+    assert_eq!(error.hresult(), 0x8007_1126_u32 as HRESULT);
+    assert_eq!(
+        error.to_string(),
+        "unable to virtualize \"root\": the root is not marked as a virtualization root \
+         (HRESULT 0x80071126)"
+    );
+
+    let error = StartError::Root {
+        root: PathBuf::from("root"),
+        source: RootError::MarkFailed(Error::access_denied()),
+    };
+    assert_eq!(error.hresult(), Error::access_denied().hresult());
+
+    let error = StartError::Root {
+        root: PathBuf::from("root"),
+        source: RootError::NotEmpty,
+    };
+    assert_eq!(error.hresult(), Error::directory_not_empty().hresult());
+
+    let error = StartError::Root {
+        root: PathBuf::from("root"),
+        source: RootError::Marker(crate::root::MarkerError::ForeignProvider {
+            expected: "regfs".to_string(),
+            found: "mirror".to_string(),
+        }),
+    };
+    assert_eq!(error.hresult(), Error::invalid_argument().hresult());
+    assert_eq!(
+        error.to_string(),
+        "unable to prepare virtualization root \"root\": \
+         the root belongs to provider \"mirror\", not \"regfs\""
+    );
+
+    let error = StartError::Options {
+        root: PathBuf::from("root"),
+        source: Error::invalid_argument().with_message("bad"),
+    };
+    assert_eq!(error.hresult(), Error::invalid_argument().hresult());
+    assert_eq!(
+        error.to_string(),
+        "invalid options for \"root\": bad (HRESULT 0x80070057)"
+    );
+}
@@ -1,41 +1,251 @@
-use anyhow::{Result, bail};
-use winapi::shared::guiddef::GUID;
-use winapi::um::combaseapi::CoCreateGuid;
-
-pub fn create_guid() -> GUID {
-    let mut guid: GUID = Default::default();
-    unsafe { CoCreateGuid(&mut guid) };
-    guid
-}
-
-pub fn guid_from_bytes(bytes: Vec<u8>) -> Result<GUID> {
-    if bytes.len() < 16 {
-        bail!("Not enough bytes for converting into a GUID");
-    }
-    let mut guid = bytes.into_iter();
-    Ok(GUID {
-        Data1: u32::from_be_bytes([guid.next().unwrap(), guid.next().unwrap(), guid.next().unwrap(), guid.next().unwrap()]),
-        Data2: u16::from_be_bytes([guid.next().unwrap(), guid.next().unwrap()]),
-        Data3: u16::from_be_bytes([guid.next().unwrap(), guid.next().unwrap()]),
-        Data4: [
-            guid.next().unwrap(),
-            guid.next().unwrap(),
-            guid.next().unwrap(),
-            guid.next().unwrap(),
-            guid.next().unwrap(),
-            guid.next().unwrap(),
-            guid.next().unwrap(),
-            guid.next().unwrap(),
-        ],
-    })
-}
-
-pub fn guid_to_bytes(guid: &GUID) -> Vec<u8> {
-    [
-        &guid.Data1.to_be_bytes()[..],
-        &guid.Data2.to_be_bytes(),
-        &guid.Data3.to_be_bytes(),
-        &guid.Data4,
-    ]
-    .concat()
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::{Error, Result};
+use crate::sys::GUID;
+
+/// An owned GUID that can be compared and hashed, used for enumeration
+/// session IDs, data stream IDs and virtualization instance IDs.
+///
+/// It is displayed and parsed in the registry form,
+/// `{6B29FC40-CA47-1067-B31D-00DD010662DA}`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+impl Guid {
+    pub const fn from_fields(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        Guid {
+            data1,
+            data2,
+            data3,
+            data4,
+        }
+    }
+
+    /// Generates a random (version 4) GUID.
+    pub fn new_v4() -> Result<Self> {
+        let mut bytes = [0u8; 16];
+        getrandom::fill(&mut bytes)
+            .map_err(|e| Error::other(format!("unable to generate a GUID: {}", e)))?;
+
+        let mut guid = Guid::from_bytes_be(bytes);
+        guid.data3 = (guid.data3 & 0x0FFF) | 0x4000;
+        guid.data4[0] = (guid.data4[0] & 0x3F) | 0x80;
+        Ok(guid)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Guid::default()
+    }
+
+    /// Reads a GUID with `Data1` to `Data3` in big-endian order, the order
+    /// they appear in the string form.
+    pub fn from_bytes_be(bytes: [u8; 16]) -> Self {
+        let mut data4 = [0u8; 8];
+        data4.copy_from_slice(&bytes[8..16]);
+        Guid {
+            data1: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            data2: u16::from_be_bytes([bytes[4], bytes[5]]),
+            data3: u16::from_be_bytes([bytes[6], bytes[7]]),
+            data4,
+        }
+    }
+
+    /// Reads a GUID with `Data1` to `Data3` in little-endian order, the
+    /// layout of `GUID` in memory on Windows and of .NET `Guid.ToByteArray`.
+    pub fn from_bytes_le(bytes: [u8; 16]) -> Self {
+        let mut data4 = [0u8; 8];
+        data4.copy_from_slice(&bytes[8..16]);
+        Guid {
+            data1: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            data2: u16::from_le_bytes([bytes[4], bytes[5]]),
+            data3: u16::from_le_bytes([bytes[6], bytes[7]]),
+            data4,
+        }
+    }
+
+    /// Writes the GUID with `Data1` to `Data3` in big-endian order.
+    pub fn to_bytes_be(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(&self.data1.to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.data2.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.data3.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.data4);
+        bytes
+    }
+
+    /// Writes the GUID with `Data1` to `Data3` in little-endian order.
+    pub fn to_bytes_le(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(&self.data1.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.data2.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.data3.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.data4);
+        bytes
+    }
+}
+
+impl From<GUID> for Guid {
+    fn from(guid: GUID) -> Self {
+        Guid {
+            data1: guid.Data1,
+            data2: guid.Data2,
+            data3: guid.Data3,
+            data4: guid.Data4,
+        }
+    }
+}
+
+impl From<Guid> for GUID {
+    fn from(guid: Guid) -> Self {
+        GUID {
+            Data1: guid.data1,
+            Data2: guid.data2,
+            Data3: guid.data3,
+            Data4: guid.data4,
+        }
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.data4;
+        write!(
+            f,
+            "{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}}}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )
+    }
+}
+
+impl FromStr for Guid {
+    type Err = Error;
+
+    /// Parses a GUID in the registry form. The braces are optional and the
+    /// hex digits may be of either case.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::invalid_argument().with_message(format!("invalid GUID {:?}", s));
+
+        let inner = match (s.strip_prefix('{'), s.strip_suffix('}')) {
+            (Some(_), Some(_)) => &s[1..s.len() - 1],
+            (None, None) => s,
+            _ => return Err(invalid()),
+        };
+
+        let groups = inner.split('-').collect::<Vec<_>>();
+        let lengths = [8, 4, 4, 4, 12];
+        if groups.len() != lengths.len()
+            || groups.iter().zip(lengths.iter()).any(|(group, &len)| {
+                group.len() != len || !group.bytes().all(|b| b.is_ascii_hexdigit())
+            })
+        {
+            return Err(invalid());
+        }
+
+        let hex = groups.concat();
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        Ok(Guid::from_bytes_be(bytes))
+    }
+}
+
+impl Serialize for Guid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Guid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[test]
+fn test_display_and_parse() {
+    let guid = Guid::from_fields(
+        0x6B29_FC40,
+        0xCA47,
+        0x1067,
+        [0xB3, 0x1D, 0x00, 0xDD, 0x01, 0x06, 0x62, 0xDA],
+    );
+    assert_eq!(guid.to_string(), "{6B29FC40-CA47-1067-B31D-00DD010662DA}");
+    assert_eq!(
+        "{6B29FC40-CA47-1067-B31D-00DD010662DA}".parse::<Guid>(),
+        Ok(guid)
+    );
+    assert_eq!(
+        "6b29fc40-ca47-1067-b31d-00dd010662da".parse::<Guid>(),
+        Ok(guid)
+    );
+
+    for invalid in &[
+        "",
+        "{}",
+        "{6B29FC40-CA47-1067-B31D-00DD010662DA",
+        "6B29FC40-CA47-1067-B31D-00DD010662DA}",
+        "6B29FC40CA471067B31D00DD010662DA",
+        "6B29FC40-CA47-1067-B31D00-DD010662DA",
+        "6B29FC40-CA47-1067-B31D-00DD010662DG",
+        "+B29FC40-CA47-1067-B31D-00DD010662DA",
+    ] {
+        assert!(invalid.parse::<Guid>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_ord_matches_display() {
+    let a = Guid::from_fields(1, 0xFFFF, 0xFFFF, [0xFF; 8]);
+    let b = Guid::from_fields(2, 0, 0, [0; 8]);
+    let c = Guid::from_fields(2, 0, 0, [0, 0, 0, 0, 0, 0, 0, 1]);
+    assert!(a < b && b < c);
+    assert!(a.to_string() < b.to_string() && b.to_string() < c.to_string());
+}
+
+#[test]
+fn test_new_v4() {
+    let a = Guid::new_v4().unwrap();
+    let b = Guid::new_v4().unwrap();
+    assert_ne!(a, b);
+    assert!(!a.is_nil());
+    assert!(Guid::default().is_nil());
+
+    let s = a.to_string();
+    assert_eq!(&s[15..16], "4");
+    assert!("89AB".contains(&s[20..21]));
+}
+
+#[test]
+fn test_winapi_roundtrip() {
+    let guid = Guid::from_fields(0x0102_0304, 0x0506, 0x0708, [9, 10, 11, 12, 13, 14, 15, 16]);
+    let raw: GUID = guid.into();
+    assert_eq!(raw.Data1, 0x0102_0304);
+    assert_eq!(Guid::from(raw), guid);
+}
+
+#[test]
+fn test_byte_order() {
+    let guid: Guid = "{6B29FC40-CA47-1067-B31D-00DD010662DA}".parse().unwrap();
+    let be = [
+        0x6B, 0x29, 0xFC, 0x40, 0xCA, 0x47, 0x10, 0x67, 0xB3, 0x1D, 0x00, 0xDD, 0x01, 0x06, 0x62,
+        0xDA,
+    ];
+    let le = [
+        0x40, 0xFC, 0x29, 0x6B, 0x47, 0xCA, 0x67, 0x10, 0xB3, 0x1D, 0x00, 0xDD, 0x01, 0x06, 0x62,
+        0xDA,
+    ];
+    assert_eq!(guid.to_bytes_be(), be);
+    assert_eq!(guid.to_bytes_le(), le);
+    assert_eq!(Guid::from_bytes_be(be), guid);
+    assert_eq!(Guid::from_bytes_le(le), guid);
 }
@@ -0,0 +1,224 @@
+//! Access to the virtualization instance from provider callbacks.
+//!
+//! The library owns the `PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT` of a running
+//! instance. Providers reach it through the `VirtualizationHandle` in
+//! `CallbackContext::handle`, which can also be replaced by a
+//! `RecordingHandle` to test a provider without ProjFS.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+use crate::error::Result;
+use crate::guid::Guid;
+use crate::provider::PlaceholderInfo;
+
+/// Operations on a running virtualization instance.
+pub trait VirtualizationHandle: fmt::Debug + Send + Sync {
+    /// Sends the placeholder information of `path`, relative to the
+    /// virtualization root, like `PrjWritePlaceholderInfo`.
+    fn write_placeholder_info(&self, path: &Path, info: &PlaceholderInfo) -> Result<()>;
+
+    /// Writes the contents of `buffer` to the data stream `data_stream_id`
+    /// starting at `offset`, like `PrjWriteFileData`.
+    fn write_file_data(
+        &self,
+        data_stream_id: Guid,
+        buffer: &AlignedBuffer,
+        offset: u64,
+    ) -> Result<()>;
+
+    /// Allocates a buffer suitable for `write_file_data`, like
+    /// `PrjAllocateAlignedBuffer`.
+    fn allocate_aligned_buffer(&self, size: usize) -> Result<AlignedBuffer>;
+}
+
+/// A buffer returned from `VirtualizationHandle::allocate_aligned_buffer`,
+/// released when dropped.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    free: unsafe fn(NonNull<u8>, usize),
+}
+
+// the buffer exclusively owns its memory
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocates a zeroed buffer on the Rust heap, for handles that do not
+    /// write to ProjFS.
+    pub fn zeroed(len: usize) -> Self {
+        unsafe fn free(ptr: NonNull<u8>, len: usize) {
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+                ptr.as_ptr(),
+                len,
+            )));
+        }
+
+        let data = vec![0u8; len].into_boxed_slice();
+        let ptr = NonNull::new(Box::into_raw(data) as *mut u8).unwrap();
+        unsafe { Self::from_raw_parts(ptr, len, free) }
+    }
+
+    /// Takes ownership of `len` bytes at `ptr`, released with `free`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads and writes of `len` bytes until `free` is
+    /// called with the same pointer and length.
+    pub unsafe fn from_raw_parts(
+        ptr: NonNull<u8>,
+        len: usize,
+        free: unsafe fn(NonNull<u8>, usize),
+    ) -> Self {
+        AlignedBuffer { ptr, len, free }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { (self.free)(self.ptr, self.len) };
+    }
+}
+
+impl fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuffer")
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Copies `bytes` into an aligned buffer and writes them to `data_stream_id`
+/// at `offset`. Writes nothing if `bytes` is empty.
+pub(crate) fn write_bytes(
+    handle: &dyn VirtualizationHandle,
+    data_stream_id: Guid,
+    offset: u64,
+    bytes: &[u8],
+) -> Result<()> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+
+    let mut buffer = handle.allocate_aligned_buffer(bytes.len())?;
+    buffer.copy_from_slice(bytes);
+    handle.write_file_data(data_stream_id, &buffer, offset)
+}
+
+/// A write made through a `RecordingHandle`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Write {
+    PlaceholderInfo {
+        path: PathBuf,
+        info: PlaceholderInfo,
+    },
+    FileData {
+        data_stream_id: Guid,
+        offset: u64,
+        data: Vec<u8>,
+    },
+}
+
+/// A `VirtualizationHandle` that records every write instead of sending it to
+/// ProjFS.
+#[derive(Debug, Default)]
+pub struct RecordingHandle {
+    writes: Mutex<Vec<Write>>,
+}
+
+impl RecordingHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the writes made so far, oldest first.
+    pub fn writes(&self) -> Vec<Write> {
+        self.writes.lock().unwrap().clone()
+    }
+
+    /// Returns the writes made so far and forgets them.
+    pub fn take_writes(&self) -> Vec<Write> {
+        std::mem::take(&mut *self.writes.lock().unwrap())
+    }
+}
+
+impl VirtualizationHandle for RecordingHandle {
+    fn write_placeholder_info(&self, path: &Path, info: &PlaceholderInfo) -> Result<()> {
+        self.writes.lock().unwrap().push(Write::PlaceholderInfo {
+            path: path.to_owned(),
+            info: *info,
+        });
+        Ok(())
+    }
+
+    fn write_file_data(
+        &self,
+        data_stream_id: Guid,
+        buffer: &AlignedBuffer,
+        offset: u64,
+    ) -> Result<()> {
+        self.writes.lock().unwrap().push(Write::FileData {
+            data_stream_id,
+            offset,
+            data: buffer.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn allocate_aligned_buffer(&self, size: usize) -> Result<AlignedBuffer> {
+        Ok(AlignedBuffer::zeroed(size))
+    }
+}
+
+#[test]
+fn test_recording_handle() {
+    let handle = RecordingHandle::new();
+    let mut buffer = handle.allocate_aligned_buffer(4).unwrap();
+    assert_eq!(&*buffer, [0; 4]);
+    buffer.copy_from_slice(b"data");
+
+    handle
+        .write_placeholder_info(Path::new("file"), &PlaceholderInfo::file(4))
+        .unwrap();
+    handle.write_file_data(Guid::default(), &buffer, 8).unwrap();
+
+    assert_eq!(
+        handle.take_writes(),
+        [
+            Write::PlaceholderInfo {
+                path: PathBuf::from("file"),
+                info: PlaceholderInfo::file(4),
+            },
+            Write::FileData {
+                data_stream_id: Guid::default(),
+                offset: 8,
+                data: b"data".to_vec(),
+            },
+        ]
+    );
+    assert!(handle.writes().is_empty());
+    assert!(AlignedBuffer::zeroed(0).is_empty());
+}
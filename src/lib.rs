@@ -1,10 +1,40 @@
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod collation;
 pub mod conv;
+pub mod enumeration;
+pub mod error;
 pub mod guid;
+pub mod handle;
+pub mod mapping;
+pub mod memory;
+pub mod mirror;
+pub mod notification;
 pub mod option;
+pub mod pattern;
 pub mod provider;
+pub mod root;
+pub mod sim;
+pub mod sys;
+#[cfg(test)]
+mod testutil;
+mod unicode;
 
 pub use crate::{
-    option::{NotificationType, OptionBuilder},
-    provider::{Provider, ProviderT},
+    error::{Error, Result, StartError, StartFailure},
+    guid::Guid,
+    handle::{AlignedBuffer, RecordingHandle, VirtualizationHandle},
+    mapping::NotificationMappings,
+    memory::InMemoryProvider,
+    mirror::MirrorProvider,
+    notification::{Notification, Verdict},
+    option::{ConfigError, NotificationType, OptionBuilder, StartOptions},
+    provider::{
+        CallbackContext, CallbackFlags, DirEntryBuffer, FileBasicInfo, PlaceholderInfo, Provider,
+        ProviderT, SourceProvider,
+    },
+    root::{MarkDirectory, MarkerError, RootConfig, RootError, RootMetadata, RootMode},
 };
-pub use winapi::um::projectedfslib as sys;
+
+#[cfg(feature = "archive")]
+pub use crate::archive::{ArchiveFormat, ArchiveProvider};
@@ -0,0 +1,223 @@
+//! Notification mappings: which notifications ProjFS sends for each subtree
+//! of the virtualization root.
+//!
+//! A mapping pairs a path relative to the root with a `NotificationType`
+//! mask. ProjFS applies the mapping of the deepest root that contains a
+//! path, and expects the array to list every root after its parents.
+//! `NotificationMappings` keeps its entries in that order, normalizes `/` and
+//! `\` separators, and can answer which mask applies to a path without
+//! mounting anything.
+
+use std::cmp::Ordering;
+use std::path::Path;
+
+use crate::collation;
+use crate::error::{Error, Result};
+use crate::option::NotificationType;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Mapping {
+    components: Vec<String>,
+    mask: NotificationType,
+}
+
+impl Mapping {
+    fn path(&self) -> String {
+        self.components.join("\\")
+    }
+}
+
+/// A validated, ordered set of notification mappings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NotificationMappings {
+    // sorted with `compare_components`, so parents come before children
+    entries: Vec<Mapping>,
+}
+
+impl NotificationMappings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the notifications for `path`, relative to the virtualization
+    /// root; an empty path is the root itself.
+    ///
+    /// Adding the same path and mask again has no effect. Adding the same
+    /// path with a different mask, an absolute path or a path containing
+    /// `..` fails.
+    pub fn add<P: AsRef<Path>>(mut self, path: P, mask: NotificationType) -> Result<Self> {
+        let path = path.as_ref();
+        let components = normalize(path)?;
+
+        match self
+            .entries
+            .binary_search_by(|entry| compare_components(&entry.components, &components))
+        {
+            Ok(i) if self.entries[i].mask == mask => {}
+            Ok(i) => {
+                return Err(Error::invalid_argument().with_message(format!(
+                    "notification path {:?} is added twice, as {} and as {}",
+                    path, self.entries[i].mask, mask
+                )))
+            }
+            Err(i) => self.entries.insert(i, Mapping { components, mask }),
+        }
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the normalized paths, separated by `\`, and their masks with
+    /// every path after its parents.
+    pub fn iter(&self) -> impl Iterator<Item = (String, NotificationType)> + '_ {
+        self.entries.iter().map(|entry| (entry.path(), entry.mask))
+    }
+
+    /// Returns the mask ProjFS applies to `path`: the one of the deepest
+    /// mapping containing it, or `NONE` if no mapping does. Names are
+    /// compared without regard to case.
+    pub fn mask_for<P: AsRef<Path>>(&self, path: P) -> NotificationType {
+        let components = match normalize(path.as_ref()) {
+            Ok(components) => components,
+            Err(_) => return NotificationType::NONE,
+        };
+
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| {
+                entry.components.len() <= components.len()
+                    && entry
+                        .components
+                        .iter()
+                        .zip(&components)
+                        .all(|(a, b)| collation::compare(a, b) == Ordering::Equal)
+            })
+            .map_or(NotificationType::NONE, |entry| entry.mask)
+    }
+}
+
+/// Splits `path` on both separators, dropping empty and `.` components.
+fn normalize(path: &Path) -> Result<Vec<String>> {
+    let invalid = |reason: &str| {
+        Err(Error::invalid_argument()
+            .with_message(format!("notification path {:?} {}", path, reason)))
+    };
+
+    let s = match path.to_str() {
+        Some(s) => s,
+        None => return invalid("is not valid Unicode"),
+    };
+    let bytes = s.as_bytes();
+    if s.starts_with(['/', '\\'].as_ref())
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+    {
+        return invalid("is not relative");
+    }
+
+    let mut components = Vec::new();
+    for component in s.split(['/', '\\'].as_ref()) {
+        match component {
+            "" | "." => {}
+            ".." => return invalid("leaves its parent with `..`"),
+            name => components.push(name.to_string()),
+        }
+    }
+    Ok(components)
+}
+
+/// Orders paths component by component, so a path sorts before the paths
+/// below it.
+fn compare_components(a: &[String], b: &[String]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| collation::compare(a, b))
+        .find(|&ordering| ordering != Ordering::Equal)
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+#[test]
+fn test_normalize_and_order() {
+    let mappings = NotificationMappings::new()
+        .add("b/c", NotificationType::PRE_DELETE)
+        .unwrap()
+        .add("a\\B\\.//d/", NotificationType::FILE_OPENED)
+        .unwrap()
+        .add("B", NotificationType::NONE)
+        .unwrap()
+        .add("", NotificationType::FILE_RENAMED)
+        .unwrap()
+        .add("a", NotificationType::PRE_RENAME)
+        .unwrap()
+        // an exact repeat is dropped, also with other separators or case
+        .add("./B\\C", NotificationType::PRE_DELETE)
+        .unwrap();
+
+    assert_eq!(
+        mappings.iter().collect::<Vec<_>>(),
+        [
+            ("".to_string(), NotificationType::FILE_RENAMED),
+            ("a".to_string(), NotificationType::PRE_RENAME),
+            ("a\\B\\d".to_string(), NotificationType::FILE_OPENED),
+            ("B".to_string(), NotificationType::NONE),
+            ("b\\c".to_string(), NotificationType::PRE_DELETE),
+        ]
+    );
+    assert_eq!(mappings.len(), 5);
+}
+
+#[test]
+fn test_rejected_paths() {
+    for path in &["/a", "\\a", "C:\\a", "c:a", "a/../b", "..", "a\\.."] {
+        let error = NotificationMappings::new()
+            .add(path, NotificationType::FILE_OPENED)
+            .unwrap_err();
+        assert_eq!(
+            error.hresult(),
+            Error::invalid_argument().hresult(),
+            "{}",
+            path
+        );
+    }
+
+    let error = NotificationMappings::new()
+        .add("a", NotificationType::FILE_OPENED)
+        .unwrap()
+        .add("A/", NotificationType::PRE_DELETE)
+        .unwrap_err();
+    assert_eq!(
+        error.message(),
+        Some("notification path \"A/\" is added twice, as FILE_OPENED and as PRE_DELETE")
+    );
+}
+
+#[test]
+fn test_mask_for() {
+    let mappings = NotificationMappings::new();
+    assert_eq!(mappings.mask_for("a"), NotificationType::NONE);
+
+    let mappings = mappings
+        .add("a", NotificationType::PRE_DELETE)
+        .unwrap()
+        .add("a/b", NotificationType::FILE_OPENED)
+        .unwrap();
+    assert_eq!(mappings.mask_for(""), NotificationType::NONE);
+    assert_eq!(mappings.mask_for("other"), NotificationType::NONE);
+    assert_eq!(mappings.mask_for("ab"), NotificationType::NONE);
+    assert_eq!(mappings.mask_for("a"), NotificationType::PRE_DELETE);
+    assert_eq!(mappings.mask_for("A\\c"), NotificationType::PRE_DELETE);
+    assert_eq!(mappings.mask_for("a/b"), NotificationType::FILE_OPENED);
+    assert_eq!(mappings.mask_for("a/B/c/d"), NotificationType::FILE_OPENED);
+    assert_eq!(mappings.mask_for("/a"), NotificationType::NONE);
+
+    let mappings = mappings.add("", NotificationType::FILE_RENAMED).unwrap();
+    assert_eq!(mappings.mask_for("other"), NotificationType::FILE_RENAMED);
+    assert_eq!(mappings.mask_for("ab"), NotificationType::FILE_RENAMED);
+    assert_eq!(mappings.mask_for("a/bc"), NotificationType::PRE_DELETE);
+}
@@ -0,0 +1,618 @@
+//! A provider that serves a tree held in memory.
+//!
+//! `InMemoryProvider` projects content that has no backing file system, such
+//! as generated build outputs or test fixtures. The tree is made of `Node`s:
+//! directories holding named children, and files holding either bytes or a
+//! closure that produces them on demand. It can be changed while the provider
+//! runs; ProjFS sees a change the next time it asks for a path it has not
+//! cached yet.
+//!
+//! Nodes serialize to a nested document, so a fixture can be checked in:
+//!
+//! ```json
+//! { "directory": { "docs": { "directory": { "a.md": { "file": "# a" } } } } }
+//! ```
+//!
+//! File contents are written as a string when they are valid UTF-8 and as an
+//! array of bytes otherwise. Names are looked up without regard to case.
+
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Component, Path};
+use std::sync::{Arc, RwLock};
+
+use crate::collation;
+use crate::enumeration::{DirEntry, DirectorySource, SessionTable};
+use crate::error::{Error, Result};
+use crate::provider::{CallbackContext, FileBasicInfo, PlaceholderInfo, SourceProvider};
+
+type ReadFn = dyn Fn(u64, u32) -> Result<Vec<u8>> + Send + Sync;
+
+/// The contents of a file node.
+#[derive(Clone)]
+pub enum FileContents {
+    Bytes(Vec<u8>),
+    /// Produced by calling `read` with the offset and length of each data
+    /// request. `size` is reported in the placeholder, and `read` must return
+    /// exactly the requested range of a file that long.
+    Lazy {
+        size: u64,
+        read: Arc<ReadFn>,
+    },
+}
+
+impl FileContents {
+    pub fn lazy<F>(size: u64, read: F) -> Self
+    where
+        F: Fn(u64, u32) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        FileContents::Lazy {
+            size,
+            read: Arc::new(read),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        match self {
+            FileContents::Bytes(bytes) => bytes.len() as u64,
+            FileContents::Lazy { size, .. } => *size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns at most `length` bytes starting at `offset`, calling the
+    /// closure of lazy contents for the part of the range within `size`. Fails
+    /// if the closure returns a different number of bytes.
+    pub fn read(&self, offset: u64, length: u32) -> Result<Vec<u8>> {
+        match self {
+            FileContents::Bytes(bytes) => Ok(byte_range(bytes, offset, length).to_vec()),
+            FileContents::Lazy { size, read } => {
+                let length = size.saturating_sub(offset).min(u64::from(length)) as u32;
+                if length == 0 {
+                    return Ok(Vec::new());
+                }
+
+                let bytes = read(offset, length)?;
+                if bytes.len() != length as usize {
+                    return Err(Error::other(format!(
+                        "lazy contents returned {} bytes for {} bytes at offset {}",
+                        bytes.len(),
+                        length,
+                        offset
+                    )));
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FileContents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileContents::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            FileContents::Lazy { size, .. } => f.debug_struct("Lazy").field("size", size).finish(),
+        }
+    }
+}
+
+impl PartialEq for FileContents {
+    /// Lazy contents are equal only if they share the same closure.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FileContents::Bytes(a), FileContents::Bytes(b)) => a == b,
+            (FileContents::Lazy { size: a, read: f }, FileContents::Lazy { size: b, read: g }) => {
+                a == b && Arc::ptr_eq(f, g)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<Vec<u8>> for FileContents {
+    fn from(bytes: Vec<u8>) -> Self {
+        FileContents::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for FileContents {
+    fn from(bytes: &[u8]) -> Self {
+        FileContents::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<&str> for FileContents {
+    fn from(text: &str) -> Self {
+        FileContents::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+impl Serialize for FileContents {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            FileContents::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => serializer.serialize_str(text),
+                Err(_) => bytes.serialize(serializer),
+            },
+            FileContents::Lazy { .. } => Err(ser::Error::custom(
+                "lazy file contents cannot be serialized",
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FileContents {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Bytes(Vec<u8>),
+        }
+
+        match Repr::deserialize(deserializer) {
+            Ok(Repr::Text(text)) => Ok(FileContents::Bytes(text.into_bytes())),
+            Ok(Repr::Bytes(bytes)) => Ok(FileContents::Bytes(bytes)),
+            Err(_) => Err(de::Error::custom(
+                "file contents must be a string or an array of bytes",
+            )),
+        }
+    }
+}
+
+/// A directory or file of the in-memory tree.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Node {
+    Directory(BTreeMap<String, Node>),
+    File(FileContents),
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Node::directory()
+    }
+}
+
+impl Node {
+    pub fn directory() -> Self {
+        Node::Directory(BTreeMap::new())
+    }
+
+    pub fn file<C: Into<FileContents>>(contents: C) -> Self {
+        Node::File(contents.into())
+    }
+
+    /// Adds `child` under `name`, replacing a child with the same name in
+    /// any case. Does nothing on a file node.
+    pub fn with_child<S: Into<String>>(mut self, name: S, child: Node) -> Self {
+        if let Node::Directory(children) = &mut self {
+            let name = name.into();
+            if let Some(existing) = find_child(children, &name) {
+                children.remove(&existing);
+            }
+            children.insert(name, child);
+        }
+        self
+    }
+
+    pub fn is_directory(&self) -> bool {
+        matches!(self, Node::Directory(_))
+    }
+
+    pub fn info(&self) -> FileBasicInfo {
+        match self {
+            Node::Directory(_) => FileBasicInfo::directory(),
+            Node::File(contents) => FileBasicInfo::file(contents.len()),
+        }
+    }
+
+    /// Returns the node at `path`, relative to this one.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&Node> {
+        let mut node = self;
+        for name in components(path.as_ref()).ok()? {
+            node = match node {
+                Node::Directory(children) => &children[&find_child(children, &name)?],
+                Node::File(_) => return None,
+            };
+        }
+        Some(node)
+    }
+}
+
+/// Returns the key of the child named `name` in any case.
+fn find_child(children: &BTreeMap<String, Node>, name: &str) -> Option<String> {
+    if children.contains_key(name) {
+        return Some(name.to_string());
+    }
+    children
+        .keys()
+        .find(|key| collation::compare(key, name) == Ordering::Equal)
+        .cloned()
+}
+
+/// Fails if a directory under `node`, at `path`, has two children ProjFS
+/// would take for the same name.
+fn check_names(node: &Node, path: &Path) -> Result<()> {
+    let children = match node {
+        Node::Directory(children) => children,
+        Node::File(_) => return Ok(()),
+    };
+
+    let mut names: Vec<&String> = children.keys().collect();
+    names.sort_by(|a, b| collation::compare(a, b));
+    if let Some(pair) = names
+        .windows(2)
+        .find(|pair| collation::compare(pair[0], pair[1]) == Ordering::Equal)
+    {
+        return Err(Error::already_exists().with_message(format!(
+            "{:?} and {:?} in {:?} differ only in case",
+            pair[0], pair[1], path
+        )));
+    }
+
+    children
+        .iter()
+        .try_for_each(|(name, child)| check_names(child, &path.join(name)))
+}
+
+/// Returns at most `length` bytes of `bytes` starting at `offset`.
+fn byte_range(bytes: &[u8], offset: u64, length: u32) -> &[u8] {
+    let start = (offset as usize).min(bytes.len());
+    let end = start.saturating_add(length as usize).min(bytes.len());
+    &bytes[start..end]
+}
+
+/// Splits a path relative to the root into names.
+fn components(path: &Path) -> Result<Vec<String>> {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str().map(str::to_string).ok_or_else(|| {
+                Error::invalid_argument().with_message(format!("{:?} is not valid Unicode", path))
+            }),
+            _ => Err(Error::invalid_argument()
+                .with_message(format!("{:?} is not relative to the root", path))),
+        })
+        .collect()
+}
+
+/// Serves the virtualization root from an in-memory tree.
+#[derive(Debug, Default)]
+pub struct InMemoryProvider {
+    root: RwLock<Node>,
+    sessions: SessionTable,
+}
+
+impl InMemoryProvider {
+    /// Starts with an empty root directory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `root`, which must be a directory whose children all have
+    /// names that differ from their siblings' in more than case.
+    pub fn from_node(root: Node) -> Result<Self> {
+        if !root.is_directory() {
+            return Err(Error::not_a_directory().with_message("the root must be a directory"));
+        }
+        check_names(&root, Path::new(""))?;
+        Ok(InMemoryProvider {
+            root: RwLock::new(root),
+            sessions: SessionTable::new(),
+        })
+    }
+
+    /// Returns a copy of the whole tree.
+    pub fn snapshot(&self) -> Node {
+        self.root.read().unwrap().clone()
+    }
+
+    /// Returns a copy of the node at `path`.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<Node> {
+        self.root.read().unwrap().get(path).cloned()
+    }
+
+    /// Creates the directory at `path` and any missing parents.
+    pub fn insert_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut root = self.root.write().unwrap();
+        let mut node = &mut *root;
+        for name in components(path)? {
+            node = Self::child_dir(node, name, path)?;
+        }
+        Ok(())
+    }
+
+    /// Creates or replaces the file at `path`, creating missing parent
+    /// directories.
+    pub fn insert_file<P: AsRef<Path>, C: Into<FileContents>>(
+        &self,
+        path: P,
+        contents: C,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let mut names = components(path)?;
+        let name = names
+            .pop()
+            .ok_or_else(|| Error::invalid_argument().with_message("the root cannot be a file"))?;
+
+        let mut root = self.root.write().unwrap();
+        let mut node = &mut *root;
+        for parent in names {
+            node = Self::child_dir(node, parent, path)?;
+        }
+        let children = match node {
+            Node::Directory(children) => children,
+            Node::File(_) => unreachable!("child_dir returns directories"),
+        };
+
+        let key = find_child(children, &name).unwrap_or(name);
+        if matches!(children.get(&key), Some(Node::Directory(_))) {
+            return Err(Error::already_exists().with_message(format!("{:?} is a directory", path)));
+        }
+        children.insert(key, Node::File(contents.into()));
+        Ok(())
+    }
+
+    /// Removes the node at `path` with everything below it.
+    pub fn remove<P: AsRef<Path>>(&self, path: P) -> Result<Node> {
+        let path = path.as_ref();
+        let mut names = components(path)?;
+        let name = names
+            .pop()
+            .ok_or_else(|| Error::invalid_argument().with_message("the root cannot be removed"))?;
+
+        let mut root = self.root.write().unwrap();
+        let mut node = &mut *root;
+        for parent in names {
+            node = match node {
+                Node::Directory(children) => {
+                    let key = find_child(children, &parent).ok_or_else(Error::not_found)?;
+                    children.get_mut(&key).unwrap()
+                }
+                Node::File(_) => return Err(Error::not_found()),
+            };
+        }
+        match node {
+            Node::Directory(children) => {
+                let key = find_child(children, &name).ok_or_else(Error::not_found)?;
+                Ok(children.remove(&key).unwrap())
+            }
+            Node::File(_) => Err(Error::not_found()),
+        }
+    }
+
+    /// Returns the child directory `name` of `node`, creating it if needed.
+    fn child_dir<'a>(node: &'a mut Node, name: String, path: &Path) -> Result<&'a mut Node> {
+        let children = match node {
+            Node::Directory(children) => children,
+            Node::File(_) => {
+                return Err(Error::not_a_directory()
+                    .with_message(format!("a parent of {:?} is a file", path)))
+            }
+        };
+        let key = find_child(children, &name).unwrap_or(name);
+        let child = children.entry(key).or_insert_with(Node::directory);
+        if !child.is_directory() {
+            return Err(
+                Error::not_a_directory().with_message(format!("a parent of {:?} is a file", path))
+            );
+        }
+        Ok(child)
+    }
+
+    fn with_node<T, F: FnOnce(&Node) -> Result<T>>(&self, path: &Path, f: F) -> Result<T> {
+        let root = self.root.read().unwrap();
+        match root.get(path) {
+            Some(node) => f(node),
+            None => Err(Error::not_found()),
+        }
+    }
+}
+
+impl DirectorySource for InMemoryProvider {
+    fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        self.with_node(path, |node| match node {
+            Node::Directory(children) => Ok(children
+                .iter()
+                .map(|(name, child)| DirEntry {
+                    name: name.into(),
+                    info: child.info(),
+                })
+                .collect()),
+            Node::File(_) => Err(Error::not_a_directory()),
+        })
+    }
+}
+
+impl SourceProvider for InMemoryProvider {
+    fn sessions(&self) -> &SessionTable {
+        &self.sessions
+    }
+
+    fn placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo> {
+        self.with_node(&context.file_path, |node| Ok(node.info().into()))
+    }
+
+    fn file_data(&self, context: &CallbackContext, offset: u64, length: u32) -> Result<Vec<u8>> {
+        // only the range is copied out of stored bytes, and lazy contents are
+        // read outside the lock
+        let contents = self.with_node(&context.file_path, |node| match node {
+            Node::File(FileContents::Bytes(bytes)) => Ok(FileContents::Bytes(
+                byte_range(bytes, offset, length).to_vec(),
+            )),
+            Node::File(lazy) => Ok(lazy.clone()),
+            Node::Directory(_) => Err(Error::invalid_argument()),
+        })?;
+
+        match contents {
+            FileContents::Bytes(range) => Ok(range),
+            lazy => lazy.read(offset, length),
+        }
+    }
+
+    fn lookup(&self, context: &CallbackContext) -> Result<()> {
+        self.with_node(&context.file_path, |_| Ok(()))
+    }
+}
+
+#[cfg(test)]
+fn fixture() -> InMemoryProvider {
+    let provider = InMemoryProvider::new();
+    provider.insert_file("readme.txt", "hello").unwrap();
+    provider.insert_file("docs/a.md", "# a").unwrap();
+    provider.insert_dir("docs/empty").unwrap();
+    provider
+}
+
+#[test]
+fn test_tree_edits() {
+    let provider = fixture();
+    assert_eq!(provider.get("README.TXT"), Some(Node::file("hello")));
+    assert!(provider.get("docs/Empty").unwrap().is_directory());
+    assert_eq!(provider.get("readme.txt/x"), None);
+
+    // replacing keeps the original spelling of the name
+    provider.insert_file("DOCS/A.md", "# A").unwrap();
+    assert_eq!(provider.get("docs/a.md"), Some(Node::file("# A")));
+    assert!(
+        matches!(provider.snapshot(), Node::Directory(children) if children.contains_key("docs"))
+    );
+
+    assert_eq!(
+        provider
+            .insert_file("readme.txt/x", "")
+            .unwrap_err()
+            .hresult(),
+        Error::not_a_directory().hresult()
+    );
+    assert_eq!(
+        provider.insert_file("docs", "").unwrap_err().hresult(),
+        Error::already_exists().hresult()
+    );
+    assert_eq!(
+        provider.insert_dir("readme.txt").unwrap_err().hresult(),
+        Error::not_a_directory().hresult()
+    );
+    assert!(provider.insert_file("", "").is_err());
+    assert!(provider.insert_file("../x", "").is_err());
+
+    assert_eq!(provider.remove("Docs/a.md"), Ok(Node::file("# A")));
+    assert_eq!(provider.remove("docs/a.md"), Err(Error::not_found()));
+    assert!(provider.remove("docs").unwrap().get("empty").is_some());
+    assert_eq!(provider.get("docs"), None);
+    assert!(InMemoryProvider::from_node(Node::file("x")).is_err());
+}
+
+#[test]
+fn test_serve_tree() {
+    use crate::sim::SimHost;
+    use std::ffi::OsStr;
+
+    let requested = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let ranges = requested.clone();
+    let provider = fixture();
+    provider
+        .insert_file(
+            "docs/generated.bin",
+            FileContents::lazy(6, move |offset, length| {
+                ranges.lock().unwrap().push((offset, length));
+                let start = offset as usize;
+                Ok(b"abcdef"[start..start + length as usize].to_vec())
+            }),
+        )
+        .unwrap();
+    let host = SimHost::new(provider).chunk_size(4);
+
+    let names = |entries| crate::testutil::entry_names(entries, |info| *info);
+    assert_eq!(
+        names(host.read_dir("", None).unwrap()),
+        [
+            ("docs".to_string(), FileBasicInfo::directory()),
+            ("readme.txt".to_string(), FileBasicInfo::file(5)),
+        ]
+    );
+    assert_eq!(
+        names(host.read_dir("docs", Some(OsStr::new("*.*"))).unwrap()),
+        [
+            ("a.md".to_string(), FileBasicInfo::file(3)),
+            ("generated.bin".to_string(), FileBasicInfo::file(6)),
+        ]
+    );
+    assert_eq!(
+        host.read_dir("readme.txt", None).unwrap_err(),
+        Error::not_a_directory()
+    );
+
+    assert_eq!(host.read_file("readme.txt").unwrap(), b"hello");
+    assert!(requested.lock().unwrap().is_empty());
+    assert_eq!(host.read_file("Docs/Generated.bin").unwrap(), b"abcdef");
+    assert_eq!(*requested.lock().unwrap(), [(0, 4), (4, 2)]);
+
+    // a lazy file must produce the size its placeholder reported
+    host.provider()
+        .insert_file(
+            "docs/short.bin",
+            FileContents::lazy(8, |_, _| Ok(b"abc".to_vec())),
+        )
+        .unwrap();
+    assert_eq!(
+        host.read_file("docs/short.bin").unwrap_err().hresult(),
+        Error::other("").hresult()
+    );
+
+    assert_eq!(
+        host.placeholder_info("docs/missing").unwrap_err(),
+        Error::not_found()
+    );
+    host.provider().insert_file("docs/missing", "now").unwrap();
+    assert_eq!(
+        host.placeholder_info("docs/missing").unwrap(),
+        PlaceholderInfo::file(3)
+    );
+    assert!(host.query_file_name("docs/empty").is_ok());
+    assert!(host.query_file_name("nothing").is_err());
+    assert!(host.provider().sessions.is_empty());
+}
+
+#[test]
+fn test_node_serialization() {
+    let tree = Node::directory()
+        .with_child("readme.txt", Node::file("hello"))
+        .with_child(
+            "bin",
+            Node::directory().with_child("blob", Node::file(vec![0xff, 0])),
+        );
+
+    let json = serde_json::to_string(&tree).unwrap();
+    assert_eq!(
+        json,
+        r#"{"directory":{"bin":{"directory":{"blob":{"file":[255,0]}}},"readme.txt":{"file":"hello"}}}"#
+    );
+    assert_eq!(serde_json::from_str::<Node>(&json).unwrap(), tree);
+
+    let provider = InMemoryProvider::from_node(serde_json::from_str(&json).unwrap()).unwrap();
+    assert_eq!(provider.get("bin/blob"), Some(Node::file(vec![0xff, 0])));
+
+    let lazy =
+        Node::directory().with_child("x", Node::file(FileContents::lazy(0, |_, _| Ok(vec![]))));
+    assert!(serde_json::to_string(&lazy).is_err());
+    assert!(serde_json::from_str::<Node>(r#"{"file": 1}"#).is_err());
+
+    // siblings differing only in case are accepted as a node, but not served
+    let json =
+        r#"{"directory":{"d":{"directory":{"A":{"file":""},"b":{"file":""},"a":{"file":""}}}}}"#;
+    let tree = serde_json::from_str::<Node>(json).unwrap();
+    let error = InMemoryProvider::from_node(tree).unwrap_err();
+    assert_eq!(error.hresult(), Error::already_exists().hresult());
+    assert_eq!(
+        error.message(),
+        Some("\"A\" and \"a\" in \"d\" differ only in case")
+    );
+}
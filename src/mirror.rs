@@ -0,0 +1,239 @@
+//! A provider that projects a backing directory.
+//!
+//! `MirrorProvider` serves the virtualization root as a lazy view of another
+//! directory read through `std::fs`: enumerations list the backing
+//! directories, placeholders carry the sizes, timestamps and attributes of
+//! the backing files, and file data is read in the ranges ProjFS requests.
+//! Changes made under the virtualization root are never written back.
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::enumeration::{DirEntry, DirectorySource, SessionTable};
+use crate::error::{Error, Result};
+use crate::provider::{
+    to_filetime, CallbackContext, FileBasicInfo, PlaceholderInfo, SourceProvider,
+};
+
+#[cfg(not(windows))]
+const FILE_ATTRIBUTE_READONLY: u32 = 0x0000_0001;
+#[cfg(not(windows))]
+const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x0000_0010;
+
+/// Describes a backing file or directory. Timestamps the platform does not
+/// record are left at `0`.
+fn basic_info(metadata: &Metadata) -> FileBasicInfo {
+    let time = |time: io::Result<SystemTime>| time.map(to_filetime).unwrap_or(0);
+    let last_write_time = time(metadata.modified());
+
+    FileBasicInfo {
+        is_directory: metadata.is_dir(),
+        file_size: if metadata.is_dir() { 0 } else { metadata.len() },
+        creation_time: time(metadata.created()),
+        last_access_time: time(metadata.accessed()),
+        last_write_time,
+        change_time: last_write_time,
+        file_attributes: file_attributes(metadata),
+    }
+}
+
+#[cfg(windows)]
+fn file_attributes(metadata: &Metadata) -> u32 {
+    use std::os::windows::fs::MetadataExt;
+    metadata.file_attributes()
+}
+
+#[cfg(not(windows))]
+fn file_attributes(metadata: &Metadata) -> u32 {
+    let mut attributes = 0;
+    if metadata.is_dir() {
+        attributes |= FILE_ATTRIBUTE_DIRECTORY;
+    }
+    if metadata.permissions().readonly() {
+        attributes |= FILE_ATTRIBUTE_READONLY;
+    }
+    attributes
+}
+
+/// Serves the virtualization root from a backing directory.
+#[derive(Debug)]
+pub struct MirrorProvider {
+    source: PathBuf,
+    sessions: SessionTable,
+}
+
+impl MirrorProvider {
+    /// Mirrors the directory at `source`.
+    pub fn new<P: Into<PathBuf>>(source: P) -> Self {
+        MirrorProvider {
+            source: source.into(),
+            sessions: SessionTable::new(),
+        }
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Maps `path`, relative to the virtualization root, into the backing
+    /// directory. Paths that would leave it are not found.
+    fn backing_path(&self, path: &Path) -> Result<PathBuf> {
+        let mut backing = self.source.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => backing.push(name),
+                Component::CurDir => {}
+                _ => {
+                    return Err(Error::not_found()
+                        .with_message(format!("{:?} is outside the mirrored directory", path)))
+                }
+            }
+        }
+        Ok(backing)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        Ok(fs::metadata(self.backing_path(path)?)?)
+    }
+}
+
+impl DirectorySource for MirrorProvider {
+    fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.backing_path(path)?)? {
+            let entry = entry?;
+            // follows links, so dangling ones are left out
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            entries.push(DirEntry {
+                name: entry.file_name(),
+                info: basic_info(&metadata),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+impl SourceProvider for MirrorProvider {
+    fn sessions(&self) -> &SessionTable {
+        &self.sessions
+    }
+
+    fn placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo> {
+        Ok(basic_info(&self.metadata(&context.file_path)?).into())
+    }
+
+    fn file_data(&self, context: &CallbackContext, offset: u64, length: u32) -> Result<Vec<u8>> {
+        let mut file = File::open(self.backing_path(&context.file_path)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut bytes = Vec::with_capacity(length as usize);
+        file.take(u64::from(length)).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn lookup(&self, context: &CallbackContext) -> Result<()> {
+        self.metadata(&context.file_path).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+use crate::testutil::{entry_names, test_context, TempDir};
+
+#[cfg(test)]
+fn temp_source() -> TempDir {
+    let dir = TempDir::new("mirror");
+    fs::create_dir_all(dir.join("docs").join("empty")).unwrap();
+    fs::write(dir.join("readme.txt"), b"hello, mirror").unwrap();
+    fs::write(dir.join("docs").join("a.md"), b"# a").unwrap();
+    fs::write(dir.join("docs").join("B.md"), vec![7u8; 10_000]).unwrap();
+    dir
+}
+
+#[test]
+fn test_mirror_enumeration_and_placeholders() {
+    use crate::sim::SimHost;
+    use std::ffi::OsStr;
+
+    let source = temp_source();
+    let host = SimHost::new(MirrorProvider::new(source.path())).entries_per_call(1);
+
+    let names = |entries| entry_names(entries, |info| info.is_directory);
+    assert_eq!(
+        names(host.read_dir("", None).unwrap()),
+        [
+            ("docs".to_string(), true),
+            ("readme.txt".to_string(), false)
+        ]
+    );
+    assert_eq!(
+        names(host.read_dir("docs", Some(OsStr::new("*.md"))).unwrap()),
+        [("a.md".to_string(), false), ("B.md".to_string(), false)]
+    );
+    assert!(host.read_dir("docs/empty", None).unwrap().is_empty());
+    assert_eq!(
+        host.read_dir("missing", None).unwrap_err().hresult(),
+        Error::not_found().hresult()
+    );
+    assert!(host.provider().sessions.is_empty());
+
+    let info = host.placeholder_info("readme.txt").unwrap().basic_info;
+    let modified = fs::metadata(source.join("readme.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert!(!info.is_directory);
+    assert_eq!(info.file_size, 13);
+    assert_eq!(info.last_write_time, to_filetime(modified));
+    assert_eq!(info.change_time, info.last_write_time);
+
+    let info = host.placeholder_info("docs").unwrap().basic_info;
+    assert!(info.is_directory);
+    assert_eq!(info.file_size, 0);
+    assert_ne!(info.file_attributes & 0x10, 0);
+
+    assert_eq!(
+        host.placeholder_info("missing").unwrap_err().hresult(),
+        Error::not_found().hresult()
+    );
+    assert_eq!(
+        host.placeholder_info("../outside").unwrap_err().hresult(),
+        Error::not_found().hresult()
+    );
+    assert!(host.query_file_name("docs/a.md").is_ok());
+    assert!(host.query_file_name("docs/c.md").is_err());
+}
+
+#[test]
+fn test_mirror_file_data() {
+    use crate::handle::Write;
+    use crate::sim::SimHost;
+
+    let source = temp_source();
+    let host = SimHost::new(MirrorProvider::new(source.path())).chunk_size(4096);
+
+    assert_eq!(host.read_file("readme.txt").unwrap(), b"hello, mirror");
+    assert_eq!(host.read_file("docs/B.md").unwrap(), vec![7u8; 10_000]);
+    assert!(host.read_file("docs").is_err());
+
+    let offsets = host
+        .take_writes()
+        .into_iter()
+        .filter_map(|write| match write {
+            Write::FileData { offset, data, .. } => Some((offset, data.len())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(offsets, [(0, 13), (0, 4096), (4096, 4096), (8192, 1808)]);
+
+    // a range past the end returns what is there
+    let provider = host.provider();
+    let context = test_context("readme.txt", crate::provider::CallbackFlags::empty());
+    assert_eq!(provider.file_data(&context, 7, 100).unwrap(), b"mirror");
+    assert!(provider.file_data(&context, 100, 1).unwrap().is_empty());
+}
@@ -0,0 +1,368 @@
+//! Notifications about file system operations in the virtualization root.
+//!
+//! ProjFS reports every notification through one callback taking a
+//! `PRJ_NOTIFICATION` value, a destination path and a
+//! `PRJ_NOTIFICATION_PARAMETERS` union whose meaning depends on the value.
+//! `Notification` decodes these into one variant per notification with its
+//! payload, so providers never touch the union.
+//!
+//! Providers answer with a `Verdict`. Besides allowing or denying the
+//! operation, the answer to a notification after a file was opened, created,
+//! overwritten or renamed can change which notifications ProjFS sends for
+//! that file from then on; the library writes the new mask back into the
+//! parameters.
+
+use log::warn;
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+use crate::option::NotificationType;
+use crate::sys::{self as prjfs, PRJ_NOTIFICATION, PRJ_NOTIFICATION_PARAMETERS};
+
+/// The answer of a provider to a notification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Let the operation proceed.
+    Allow,
+    /// Fail the operation with the error. Only `Pre*` operations can be
+    /// denied; ProjFS ignores errors for the others.
+    Deny(Error),
+    /// Let the operation proceed and replace the notifications sent for this
+    /// file from now on. Only accepted where `Notification::accepts_mask`
+    /// returns `true`; elsewhere it is the same as `Allow`.
+    AllowWithMask(NotificationType),
+}
+
+impl From<Result<()>> for Verdict {
+    fn from(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => Verdict::Allow,
+            Err(e) => Verdict::Deny(e),
+        }
+    }
+}
+
+impl Verdict {
+    /// Carries out the verdict for a notification of `kind`, writing a new
+    /// mask into `parameters`. Returns the error to report to ProjFS.
+    pub(crate) fn apply(
+        self,
+        kind: NotificationType,
+        parameters: &mut PRJ_NOTIFICATION_PARAMETERS,
+    ) -> Result<()> {
+        match self {
+            Verdict::Allow => Ok(()),
+            Verdict::Deny(e) => Err(e),
+            Verdict::AllowWithMask(mask) => {
+                // the union member ProjFS reads back depends on the notification
+                let target = unsafe {
+                    if kind == NotificationType::FILE_RENAMED {
+                        &mut parameters.FileRenamed_mut().NotificationMask
+                    } else if accepts_mask(kind) {
+                        &mut parameters.PostCreate_mut().NotificationMask
+                    } else {
+                        warn!("notify: {:?} does not accept a notification mask", kind);
+                        return Ok(());
+                    }
+                };
+                *target = mask.into_raw();
+                Ok(())
+            }
+        }
+    }
+}
+
+fn accepts_mask(kind: NotificationType) -> bool {
+    kind == NotificationType::FILE_OPENED
+        || kind == NotificationType::NEW_FILE_CREATED
+        || kind == NotificationType::FILE_OVERWRITTEN
+        || kind == NotificationType::FILE_RENAMED
+}
+
+/// A notification sent to `ProviderT::notify`.
+///
+/// Paths are relative to the virtualization root. A `destination` is empty
+/// when it lies outside the root. An error returned for a `Pre*`
+/// notification denies the operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    /// A handle was opened to an existing file or directory.
+    FileOpened,
+    /// A new file or directory was created.
+    NewFileCreated,
+    /// An existing file was superseded or overwritten.
+    FileOverwritten,
+    /// A file or directory is about to be deleted.
+    PreDelete,
+    /// A file or directory is about to be renamed to `destination`.
+    PreRename { destination: PathBuf },
+    /// A hard link to a file is about to be created at `destination`.
+    PreSetHardlink { destination: PathBuf },
+    /// A file or directory was renamed to `destination`.
+    FileRenamed { destination: PathBuf },
+    /// A hard link to a file was created at `destination`.
+    HardlinkCreated { destination: PathBuf },
+    /// A handle was closed. `modified` tells whether the file was written
+    /// through any handle and `deleted` whether it was deleted on close.
+    HandleClosed { modified: bool, deleted: bool },
+    /// A placeholder is about to be converted to a full file, whose contents
+    /// no longer come from the provider.
+    PreConvertToFull,
+}
+
+impl Notification {
+    /// Decodes the arguments of the ProjFS notification callback.
+    /// `destination` is the `DestinationFileName`, empty if there is none.
+    pub(crate) fn from_raw(
+        notification: PRJ_NOTIFICATION,
+        destination: PathBuf,
+        parameters: &PRJ_NOTIFICATION_PARAMETERS,
+    ) -> Result<Self> {
+        let decoded = match notification {
+            prjfs::PRJ_NOTIFICATION_FILE_OPENED => Notification::FileOpened,
+            prjfs::PRJ_NOTIFICATION_NEW_FILE_CREATED => Notification::NewFileCreated,
+            prjfs::PRJ_NOTIFICATION_FILE_OVERWRITTEN => Notification::FileOverwritten,
+            prjfs::PRJ_NOTIFICATION_PRE_DELETE => Notification::PreDelete,
+            prjfs::PRJ_NOTIFICATION_PRE_RENAME => Notification::PreRename { destination },
+            prjfs::PRJ_NOTIFICATION_PRE_SET_HARDLINK => {
+                Notification::PreSetHardlink { destination }
+            }
+            prjfs::PRJ_NOTIFICATION_FILE_RENAMED => Notification::FileRenamed { destination },
+            prjfs::PRJ_NOTIFICATION_HARDLINK_CREATED => {
+                Notification::HardlinkCreated { destination }
+            }
+            prjfs::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_NO_MODIFICATION => {
+                Notification::HandleClosed {
+                    modified: false,
+                    deleted: false,
+                }
+            }
+            prjfs::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED => {
+                Notification::HandleClosed {
+                    modified: true,
+                    deleted: false,
+                }
+            }
+            prjfs::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_DELETED => {
+                // every member of the union is plain data, so reading the one
+                // ProjFS filled in for this notification is always defined
+                let deleted = unsafe { parameters.FileDeletedOnHandleClose() };
+                Notification::HandleClosed {
+                    modified: deleted.IsFileModified != 0,
+                    deleted: true,
+                }
+            }
+            prjfs::PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL => Notification::PreConvertToFull,
+            other => {
+                return Err(Error::invalid_argument()
+                    .with_message(format!("unknown notification 0x{:08X}", other)))
+            }
+        };
+        Ok(decoded)
+    }
+
+    /// Returns the flag that subscribes to this notification.
+    pub fn kind(&self) -> NotificationType {
+        match self {
+            Notification::FileOpened => NotificationType::FILE_OPENED,
+            Notification::NewFileCreated => NotificationType::NEW_FILE_CREATED,
+            Notification::FileOverwritten => NotificationType::FILE_OVERWRITTEN,
+            Notification::PreDelete => NotificationType::PRE_DELETE,
+            Notification::PreRename { .. } => NotificationType::PRE_RENAME,
+            Notification::PreSetHardlink { .. } => NotificationType::PRE_SET_HARDLINK,
+            Notification::FileRenamed { .. } => NotificationType::FILE_RENAMED,
+            Notification::HardlinkCreated { .. } => NotificationType::HARDLINK_CREATED,
+            Notification::HandleClosed { deleted: true, .. } => {
+                NotificationType::FILE_HANDLE_CLOSED_FILE_DELETED
+            }
+            Notification::HandleClosed { modified: true, .. } => {
+                NotificationType::FILE_HANDLE_CLOSED_FILE_MODIFIED
+            }
+            Notification::HandleClosed { .. } => {
+                NotificationType::FILE_HANDLE_CLOSED_NO_MODIFICATION
+            }
+            Notification::PreConvertToFull => NotificationType::FILE_PRE_CONVERT_TO_FULL,
+        }
+    }
+
+    /// Returns `true` if `Verdict::AllowWithMask` can change the notifications
+    /// of the file.
+    pub fn accepts_mask(&self) -> bool {
+        accepts_mask(self.kind())
+    }
+
+    /// Returns `true` for notifications sent before an operation, which the
+    /// provider can deny.
+    pub fn is_pre_operation(&self) -> bool {
+        matches!(
+            self,
+            Notification::PreDelete
+                | Notification::PreRename { .. }
+                | Notification::PreSetHardlink { .. }
+                | Notification::PreConvertToFull
+        )
+    }
+}
+
+#[test]
+fn test_from_raw() {
+    let parameters = PRJ_NOTIFICATION_PARAMETERS::default();
+    let destination = PathBuf::from("dir\\renamed");
+    let decode = |notification| {
+        Notification::from_raw(notification, destination.clone(), &parameters).unwrap()
+    };
+
+    let expected = [
+        (
+            prjfs::PRJ_NOTIFICATION_FILE_OPENED,
+            Notification::FileOpened,
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_NEW_FILE_CREATED,
+            Notification::NewFileCreated,
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_FILE_OVERWRITTEN,
+            Notification::FileOverwritten,
+        ),
+        (prjfs::PRJ_NOTIFICATION_PRE_DELETE, Notification::PreDelete),
+        (
+            prjfs::PRJ_NOTIFICATION_PRE_RENAME,
+            Notification::PreRename {
+                destination: destination.clone(),
+            },
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_PRE_SET_HARDLINK,
+            Notification::PreSetHardlink {
+                destination: destination.clone(),
+            },
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_FILE_RENAMED,
+            Notification::FileRenamed {
+                destination: destination.clone(),
+            },
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_HARDLINK_CREATED,
+            Notification::HardlinkCreated {
+                destination: destination.clone(),
+            },
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_NO_MODIFICATION,
+            Notification::HandleClosed {
+                modified: false,
+                deleted: false,
+            },
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED,
+            Notification::HandleClosed {
+                modified: true,
+                deleted: false,
+            },
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_DELETED,
+            Notification::HandleClosed {
+                modified: false,
+                deleted: true,
+            },
+        ),
+        (
+            prjfs::PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL,
+            Notification::PreConvertToFull,
+        ),
+    ];
+    for (raw, notification) in expected.iter() {
+        assert_eq!(&decode(*raw), notification);
+        assert_eq!(notification.kind().bits(), *raw);
+    }
+
+    assert!(Notification::from_raw(0, PathBuf::new(), &parameters).is_err());
+    assert!(Notification::from_raw(0x0010_0000, PathBuf::new(), &parameters).is_err());
+}
+
+#[test]
+fn test_handle_closed_deleted_reads_parameters() {
+    let mut parameters = PRJ_NOTIFICATION_PARAMETERS::default();
+    unsafe { parameters.FileDeletedOnHandleClose_mut().IsFileModified = 1 };
+
+    let notification = Notification::from_raw(
+        prjfs::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_DELETED,
+        PathBuf::new(),
+        &parameters,
+    )
+    .unwrap();
+    assert_eq!(
+        notification,
+        Notification::HandleClosed {
+            modified: true,
+            deleted: true,
+        }
+    );
+    assert_eq!(
+        notification.kind(),
+        NotificationType::FILE_HANDLE_CLOSED_FILE_DELETED
+    );
+    assert!(!notification.is_pre_operation());
+    assert!(Notification::PreDelete.is_pre_operation());
+}
+
+#[test]
+fn test_verdict_apply() {
+    let mask = NotificationType::PRE_DELETE | NotificationType::FILE_RENAMED;
+
+    for kind in &[
+        NotificationType::FILE_OPENED,
+        NotificationType::NEW_FILE_CREATED,
+        NotificationType::FILE_OVERWRITTEN,
+    ] {
+        let mut parameters = PRJ_NOTIFICATION_PARAMETERS::default();
+        Verdict::AllowWithMask(mask)
+            .apply(*kind, &mut parameters)
+            .unwrap();
+        assert_eq!(
+            unsafe { parameters.PostCreate().NotificationMask },
+            mask.bits()
+        );
+    }
+
+    let mut parameters = PRJ_NOTIFICATION_PARAMETERS::default();
+    Verdict::AllowWithMask(mask)
+        .apply(NotificationType::FILE_RENAMED, &mut parameters)
+        .unwrap();
+    assert_eq!(
+        unsafe { parameters.FileRenamed().NotificationMask },
+        mask.bits()
+    );
+
+    let mut parameters = PRJ_NOTIFICATION_PARAMETERS::default();
+    Verdict::AllowWithMask(mask)
+        .apply(NotificationType::PRE_DELETE, &mut parameters)
+        .unwrap();
+    assert_eq!(unsafe { parameters.PostCreate().NotificationMask }, 0);
+
+    assert_eq!(
+        Verdict::Deny(Error::access_denied()).apply(NotificationType::PRE_DELETE, &mut parameters),
+        Err(Error::access_denied())
+    );
+    assert_eq!(
+        Verdict::Allow.apply(NotificationType::PRE_DELETE, &mut parameters),
+        Ok(())
+    );
+    assert_eq!(
+        Verdict::from(Err(Error::not_found())),
+        Verdict::Deny(Error::not_found())
+    );
+    assert_eq!(Verdict::from(Ok(())), Verdict::Allow);
+
+    assert!(Notification::FileOpened.accepts_mask());
+    assert!(Notification::FileRenamed {
+        destination: PathBuf::new()
+    }
+    .accepts_mask());
+    assert!(!Notification::PreDelete.accepts_mask());
+}
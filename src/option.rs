@@ -1,86 +1,162 @@
 use crate::conv::{WStr, WStrExt};
-use std::path::PathBuf;
+use crate::error::{Error, Result};
+use crate::mapping::NotificationMappings;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 bitflags::bitflags! {
+    /// The notifications ProjFS sends for a file or directory. The bits are
+    /// the `PRJ_NOTIFY_*` values, so every raw mask converts losslessly.
+    ///
+    /// `USE_EXISTING_MASK` has every bit set; it keeps the mask a file
+    /// already has and only means that when used on its own.
     pub struct NotificationType: u32 {
-        const NONE = 0b0000_0000_0000_0000;
-        const SUPPRESS_NOTIFICATIONS = 0b0000_0000_0000_0001;
-        const FILE_OPENED = 0b0000_0000_0000_0010;
-        const NEW_FILE_CREATED = 0b0000_0000_0000_0100;
-        const FILE_OVERWRITTEN = 0b0000_0000_0000_1000;
-        const PRE_DELETE = 0b0000_0000_0001_0000;
-        const PRE_RENAME = 0b0000_0000_0010_0000;
-        const PRE_SET_HARDLINK = 0b0000_0000_0100_0000;
-        const FILE_RENAMED = 0b0000_0000_1000_0000;
-        const HARDLINK_CREATED  = 0b0000_0001_0000_0000;
-        const FILE_HANDLE_CLOSED_NO_MODIFICATION = 0b0000_0010_0000_0000;
-        const FILE_HANDLE_CLOSED_FILE_MODIFIED = 0b0000_0100_0000_0000;
-        const FILE_HANDLE_CLOSED_FILE_DELETED = 0b0000_1000_0000_0000;
-        const FILE_PRE_CONVERT_TO_FULL = 0b0001_0000_0000_0000;
-        const USE_EXISTING_MASK = 0b0010_0000_0000_0000;
+        const NONE = crate::sys::PRJ_NOTIFY_NONE;
+        const SUPPRESS_NOTIFICATIONS = crate::sys::PRJ_NOTIFY_SUPPRESS_NOTIFICATIONS;
+        const FILE_OPENED = crate::sys::PRJ_NOTIFY_FILE_OPENED;
+        const NEW_FILE_CREATED = crate::sys::PRJ_NOTIFY_NEW_FILE_CREATED;
+        const FILE_OVERWRITTEN = crate::sys::PRJ_NOTIFY_FILE_OVERWRITTEN;
+        const PRE_DELETE = crate::sys::PRJ_NOTIFY_PRE_DELETE;
+        const PRE_RENAME = crate::sys::PRJ_NOTIFY_PRE_RENAME;
+        const PRE_SET_HARDLINK = crate::sys::PRJ_NOTIFY_PRE_SET_HARDLINK;
+        const FILE_RENAMED = crate::sys::PRJ_NOTIFY_FILE_RENAMED;
+        const HARDLINK_CREATED = crate::sys::PRJ_NOTIFY_HARDLINK_CREATED;
+        const FILE_HANDLE_CLOSED_NO_MODIFICATION =
+            crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_NO_MODIFICATION;
+        const FILE_HANDLE_CLOSED_FILE_MODIFIED =
+            crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_MODIFIED;
+        const FILE_HANDLE_CLOSED_FILE_DELETED =
+            crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED;
+        const FILE_PRE_CONVERT_TO_FULL = crate::sys::PRJ_NOTIFY_FILE_PRE_CONVERT_TO_FULL;
+        const USE_EXISTING_MASK = crate::sys::PRJ_NOTIFY_USE_EXISTING_MASK;
     }
 }
 
+/// The single-bit flags by name, in bit order.
+const FLAG_NAMES: &[(&str, NotificationType)] = &[
+    (
+        "SUPPRESS_NOTIFICATIONS",
+        NotificationType::SUPPRESS_NOTIFICATIONS,
+    ),
+    ("FILE_OPENED", NotificationType::FILE_OPENED),
+    ("NEW_FILE_CREATED", NotificationType::NEW_FILE_CREATED),
+    ("FILE_OVERWRITTEN", NotificationType::FILE_OVERWRITTEN),
+    ("PRE_DELETE", NotificationType::PRE_DELETE),
+    ("PRE_RENAME", NotificationType::PRE_RENAME),
+    ("PRE_SET_HARDLINK", NotificationType::PRE_SET_HARDLINK),
+    ("FILE_RENAMED", NotificationType::FILE_RENAMED),
+    ("HARDLINK_CREATED", NotificationType::HARDLINK_CREATED),
+    (
+        "FILE_HANDLE_CLOSED_NO_MODIFICATION",
+        NotificationType::FILE_HANDLE_CLOSED_NO_MODIFICATION,
+    ),
+    (
+        "FILE_HANDLE_CLOSED_FILE_MODIFIED",
+        NotificationType::FILE_HANDLE_CLOSED_FILE_MODIFIED,
+    ),
+    (
+        "FILE_HANDLE_CLOSED_FILE_DELETED",
+        NotificationType::FILE_HANDLE_CLOSED_FILE_DELETED,
+    ),
+    (
+        "FILE_PRE_CONVERT_TO_FULL",
+        NotificationType::FILE_PRE_CONVERT_TO_FULL,
+    ),
+];
+
 impl NotificationType {
-    fn into_raw(&self) -> crate::sys::PRJ_NOTIFY_TYPES {
-        let mut raw = crate::sys::PRJ_NOTIFY_TYPES::default();
+    pub fn from_raw(raw: crate::sys::PRJ_NOTIFY_TYPES) -> Self {
+        // USE_EXISTING_MASK covers every bit, so nothing is truncated
+        Self::from_bits_truncate(raw)
+    }
 
-        if self.contains(NotificationType::NONE) {
-            raw = raw | crate::sys::PRJ_NOTIFY_NONE;
-        }
-        if self.contains(NotificationType::SUPPRESS_NOTIFICATIONS) {
-            raw = raw | crate::sys::PRJ_NOTIFY_SUPPRESS_NOTIFICATIONS;
-        }
-        if self.contains(NotificationType::FILE_OPENED) {
-            raw = raw | crate::sys::PRJ_NOTIFY_FILE_OPENED;
-        }
-        if self.contains(NotificationType::NEW_FILE_CREATED) {
-            raw = raw | crate::sys::PRJ_NOTIFY_NEW_FILE_CREATED;
-        }
-        if self.contains(NotificationType::FILE_OVERWRITTEN) {
-            raw = raw | crate::sys::PRJ_NOTIFY_FILE_OVERWRITTEN;
-        }
-        if self.contains(NotificationType::PRE_DELETE) {
-            raw = raw | crate::sys::PRJ_NOTIFY_PRE_DELETE;
-        }
-        if self.contains(NotificationType::PRE_RENAME) {
-            raw = raw | crate::sys::PRJ_NOTIFY_PRE_RENAME;
-        }
-        if self.contains(NotificationType::PRE_SET_HARDLINK) {
-            raw = raw | crate::sys::PRJ_NOTIFY_PRE_SET_HARDLINK;
-        }
-        if self.contains(NotificationType::FILE_RENAMED) {
-            raw = raw | crate::sys::PRJ_NOTIFY_FILE_RENAMED;
-        }
-        if self.contains(NotificationType::HARDLINK_CREATED) {
-            raw = raw | crate::sys::PRJ_NOTIFY_HARDLINK_CREATED;
-        }
-        if self.contains(NotificationType::FILE_HANDLE_CLOSED_NO_MODIFICATION) {
-            raw = raw | crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_NO_MODIFICATION;
+    pub fn into_raw(self) -> crate::sys::PRJ_NOTIFY_TYPES {
+        self.bits()
+    }
+}
+
+impl fmt::Display for NotificationType {
+    /// Writes the flag names separated by `" | "`, e.g.
+    /// `FILE_OPENED | PRE_DELETE`. Bits without a name are written in hex.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == NotificationType::USE_EXISTING_MASK {
+            return f.write_str("USE_EXISTING_MASK");
         }
-        if self.contains(NotificationType::FILE_HANDLE_CLOSED_FILE_MODIFIED) {
-            raw = raw | crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_MODIFIED;
+        if self.is_empty() {
+            return f.write_str("NONE");
         }
-        if self.contains(NotificationType::FILE_HANDLE_CLOSED_FILE_DELETED) {
-            raw = raw | crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED;
+
+        let mut rest = *self;
+        let mut separator = "";
+        for (name, flag) in FLAG_NAMES {
+            if rest.contains(*flag) {
+                write!(f, "{}{}", separator, name)?;
+                rest.remove(*flag);
+                separator = " | ";
+            }
         }
-        if self.contains(NotificationType::FILE_PRE_CONVERT_TO_FULL) {
-            raw = raw | crate::sys::PRJ_NOTIFY_FILE_PRE_CONVERT_TO_FULL;
+        if !rest.is_empty() {
+            write!(f, "{}{:#X}", separator, rest.bits())?;
         }
-        if self.contains(NotificationType::USE_EXISTING_MASK) {
-            raw = raw | crate::sys::PRJ_NOTIFY_USE_EXISTING_MASK;
+        Ok(())
+    }
+}
+
+impl FromStr for NotificationType {
+    type Err = Error;
+
+    /// Parses the `Display` form. Names may be of either case and hex
+    /// values such as `0x2000` are accepted for bits without a name.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut mask = NotificationType::NONE;
+        for part in s.split('|').map(str::trim) {
+            let invalid = || {
+                Error::invalid_argument()
+                    .with_message(format!("invalid notification type {:?} in {:?}", part, s))
+            };
+
+            let flag = if part.eq_ignore_ascii_case("NONE") {
+                NotificationType::NONE
+            } else if part.eq_ignore_ascii_case("USE_EXISTING_MASK") {
+                NotificationType::USE_EXISTING_MASK
+            } else if let Some(hex) = part.strip_prefix("0x").or_else(|| part.strip_prefix("0X")) {
+                u32::from_str_radix(hex, 16)
+                    .map(NotificationType::from_raw)
+                    .map_err(|_| invalid())?
+            } else {
+                FLAG_NAMES
+                    .iter()
+                    .find(|(name, _)| part.eq_ignore_ascii_case(name))
+                    .map(|(_, flag)| *flag)
+                    .ok_or_else(invalid)?
+            };
+            mask |= flag;
         }
+        Ok(mask)
+    }
+}
 
-        raw
+impl Serialize for NotificationType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
     }
 }
 
-#[derive(Default)]
+impl<'de> Deserialize<'de> for NotificationType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
 pub struct OptionBuilder {
     use_negative_path_cache: bool,
     pool_thread_count: Option<u32>,
     concurrent_thread_count: Option<u32>,
-    notifications: Vec<(NotificationType, WStr)>,
+    notifications: Vec<(NotificationType, PathBuf)>,
 }
 
 impl OptionBuilder {
@@ -107,12 +183,30 @@ impl OptionBuilder {
         self.add_notification(notification, "".into())
     }
 
+    /// Adds the notifications for `path`, relative to the virtualization
+    /// root. The path is checked by `build`.
     pub fn add_notification(mut self, notification: NotificationType, path: PathBuf) -> Self {
-        self.notifications.push((notification, path.to_wstr()));
+        self.notifications.push((notification, path));
         self
     }
 
-    pub(crate) fn build(&self) -> crate::sys::PRJ_STARTVIRTUALIZING_OPTIONS {
+    /// Adds every mapping of `mappings`.
+    pub fn add_notification_mappings(mut self, mappings: &NotificationMappings) -> Self {
+        self.notifications.extend(
+            mappings
+                .iter()
+                .map(|(path, notification)| (notification, path.into())),
+        );
+        self
+    }
+
+    /// Validates the options and converts them for `PrjStartVirtualizing`.
+    /// Thread counts must not be zero and the notification paths must be
+    /// valid for `NotificationMappings`, which also orders them the way
+    /// ProjFS expects.
+    pub fn build(&self) -> Result<StartOptions> {
+        let invalid = |message: String| Err(Error::invalid_argument().with_message(message));
+
         let mut options = crate::sys::PRJ_STARTVIRTUALIZING_OPTIONS::default();
 
         if self.use_negative_path_cache {
@@ -120,24 +214,558 @@ impl OptionBuilder {
         }
 
         if let Some(count) = self.pool_thread_count {
+            if count == 0 {
+                return invalid("the pool thread count must not be zero".into());
+            }
             options.PoolThreadCount = count;
         }
 
         if let Some(count) = self.concurrent_thread_count {
+            if count == 0 {
+                return invalid("the concurrent thread count must not be zero".into());
+            }
             options.ConcurrentThreadCount = count;
         }
 
-        options.NotificationMappingsCount = self.notifications.len() as u32;
-        let mut raw_notifications = self
-            .notifications
+        let mut notifications = NotificationMappings::new();
+        for (notification, path) in &self.notifications {
+            notifications = notifications.add(path, *notification)?;
+        }
+
+        let roots = notifications
+            .iter()
+            .map(|(path, _)| path.to_wstr())
+            .collect::<Vec<_>>();
+        let mut mappings = notifications
             .iter()
-            .map(|(notify, path)| crate::sys::PRJ_NOTIFICATION_MAPPING {
+            .zip(&roots)
+            .map(|((_, notify), root)| crate::sys::PRJ_NOTIFICATION_MAPPING {
                 NotificationBitMask: notify.into_raw(),
-                NotificationRoot: path.as_ptr(),
+                NotificationRoot: root.as_ptr(),
             })
             .collect::<Vec<_>>();
-        options.NotificationMappings = raw_notifications.as_mut_ptr();
+        options.NotificationMappingsCount = mappings.len() as u32;
+        options.NotificationMappings = mappings.as_mut_ptr();
+
+        Ok(StartOptions {
+            raw: options,
+            _mappings: mappings,
+            _roots: roots,
+        })
+    }
+}
+
+/// The configuration document read into an `OptionBuilder`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OptionConfig {
+    #[serde(default)]
+    use_negative_path_cache: bool,
+    #[serde(default, deserialize_with = "thread_count")]
+    pool_thread_count: Option<u32>,
+    #[serde(default, deserialize_with = "thread_count")]
+    concurrent_thread_count: Option<u32>,
+    #[serde(default)]
+    notifications: Vec<NotificationConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotificationConfig {
+    #[serde(default)]
+    path: PathBuf,
+    types: NotificationType,
+}
+
+fn thread_count<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u32>, D::Error> {
+    match u32::deserialize(deserializer)? {
+        0 => Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(0),
+            &"a thread count above zero",
+        )),
+        count => Ok(Some(count)),
+    }
+}
+
+impl From<OptionConfig> for OptionBuilder {
+    fn from(config: OptionConfig) -> Self {
+        OptionBuilder {
+            use_negative_path_cache: config.use_negative_path_cache,
+            pool_thread_count: config.pool_thread_count,
+            concurrent_thread_count: config.concurrent_thread_count,
+            notifications: config
+                .notifications
+                .into_iter()
+                .map(|n| (n.types, n.path))
+                .collect(),
+        }
+    }
+}
+
+impl OptionBuilder {
+    /// Reads the options from a TOML document such as
+    ///
+    /// ```toml
+    /// use_negative_path_cache = true
+    /// pool_thread_count = 8
+    ///
+    /// [[notifications]]
+    /// types = "FILE_OPENED | PRE_RENAME | PRE_DELETE"
+    ///
+    /// [[notifications]]
+    /// path = "HKEY_LOCAL_MACHINE/SOFTWARE"
+    /// types = "NONE"
+    /// ```
+    ///
+    /// A notification without a `path` applies to the virtualization root.
+    /// Unknown keys are rejected.
+    pub fn from_toml(toml: &str) -> std::result::Result<Self, ConfigError> {
+        let config: OptionConfig = toml::from_str(toml).map_err(|e| ConfigError::Invalid {
+            line: e
+                .span()
+                .map(|span| toml[..span.start].matches('\n').count() + 1),
+            message: e.message().to_string(),
+        })?;
+        Ok(config.into())
+    }
 
-        options
+    /// Reads the options from a JSON document with the same keys as the
+    /// TOML form.
+    pub fn from_json(json: &str) -> std::result::Result<Self, ConfigError> {
+        let config: OptionConfig = serde_json::from_str(json).map_err(|e| {
+            // serde_json appends the position, which is reported separately
+            let mut message = e.to_string();
+            if let Some(at) = message.rfind(" at line ") {
+                message.truncate(at);
+            }
+            ConfigError::Invalid {
+                line: Some(e.line()).filter(|&line| line > 0),
+                message,
+            }
+        })?;
+        Ok(config.into())
     }
+
+    /// Reads the options from a `.toml` or `.json` file.
+    pub fn read<P: AsRef<Path>>(path: P) -> std::result::Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let from_str = if extension.eq_ignore_ascii_case("toml") {
+            Self::from_toml
+        } else if extension.eq_ignore_ascii_case("json") {
+            Self::from_json
+        } else {
+            return Err(ConfigError::UnknownFormat {
+                path: path.to_path_buf(),
+            });
+        };
+
+        let bytes = std::fs::read(path)?;
+        let text = std::str::from_utf8(&bytes).map_err(|e| ConfigError::Invalid {
+            line: None,
+            message: e.to_string(),
+        })?;
+        from_str(text)
+    }
+}
+
+/// An error reading an `OptionBuilder` from a configuration document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The file could not be read.
+    Io(Error),
+    /// The file is neither `.toml` nor `.json`.
+    UnknownFormat { path: PathBuf },
+    /// The document is malformed, has an unknown key or holds an invalid
+    /// flag name or thread count.
+    Invalid {
+        line: Option<usize>,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "unable to read the options: {}", error),
+            ConfigError::UnknownFormat { path } => {
+                write!(f, "{:?} is neither a .toml nor a .json file", path)
+            }
+            ConfigError::Invalid {
+                line: Some(line),
+                message,
+            } => write!(f, "invalid options at line {}: {}", line, message),
+            ConfigError::Invalid {
+                line: None,
+                message,
+            } => write!(f, "invalid options: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(error: std::io::Error) -> Self {
+        ConfigError::Io(error.into())
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(error: ConfigError) -> Self {
+        match error {
+            ConfigError::Io(error) => error,
+            error => Error::invalid_argument().with_message(error.to_string()),
+        }
+    }
+}
+
+/// The options passed to `PrjStartVirtualizing`, returned by
+/// `OptionBuilder::build`. The raw struct points into the mapping array and
+/// path strings owned here, which live on the heap and so stay put when the
+/// value moves.
+pub struct StartOptions {
+    raw: crate::sys::PRJ_STARTVIRTUALIZING_OPTIONS,
+    _mappings: Vec<crate::sys::PRJ_NOTIFICATION_MAPPING>,
+    _roots: Vec<WStr>,
+}
+
+impl StartOptions {
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn as_raw(&self) -> &crate::sys::PRJ_STARTVIRTUALIZING_OPTIONS {
+        &self.raw
+    }
+}
+
+#[test]
+fn test_notification_type_raw_values() {
+    let raw = [
+        (NotificationType::NONE, crate::sys::PRJ_NOTIFY_NONE),
+        (
+            NotificationType::SUPPRESS_NOTIFICATIONS,
+            crate::sys::PRJ_NOTIFY_SUPPRESS_NOTIFICATIONS,
+        ),
+        (
+            NotificationType::FILE_OPENED,
+            crate::sys::PRJ_NOTIFY_FILE_OPENED,
+        ),
+        (
+            NotificationType::NEW_FILE_CREATED,
+            crate::sys::PRJ_NOTIFY_NEW_FILE_CREATED,
+        ),
+        (
+            NotificationType::FILE_OVERWRITTEN,
+            crate::sys::PRJ_NOTIFY_FILE_OVERWRITTEN,
+        ),
+        (
+            NotificationType::PRE_DELETE,
+            crate::sys::PRJ_NOTIFY_PRE_DELETE,
+        ),
+        (
+            NotificationType::PRE_RENAME,
+            crate::sys::PRJ_NOTIFY_PRE_RENAME,
+        ),
+        (
+            NotificationType::PRE_SET_HARDLINK,
+            crate::sys::PRJ_NOTIFY_PRE_SET_HARDLINK,
+        ),
+        (
+            NotificationType::FILE_RENAMED,
+            crate::sys::PRJ_NOTIFY_FILE_RENAMED,
+        ),
+        (
+            NotificationType::HARDLINK_CREATED,
+            crate::sys::PRJ_NOTIFY_HARDLINK_CREATED,
+        ),
+        (
+            NotificationType::FILE_HANDLE_CLOSED_NO_MODIFICATION,
+            crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_NO_MODIFICATION,
+        ),
+        (
+            NotificationType::FILE_HANDLE_CLOSED_FILE_MODIFIED,
+            crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_MODIFIED,
+        ),
+        (
+            NotificationType::FILE_HANDLE_CLOSED_FILE_DELETED,
+            crate::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED,
+        ),
+        (
+            NotificationType::FILE_PRE_CONVERT_TO_FULL,
+            crate::sys::PRJ_NOTIFY_FILE_PRE_CONVERT_TO_FULL,
+        ),
+        (
+            NotificationType::USE_EXISTING_MASK,
+            crate::sys::PRJ_NOTIFY_USE_EXISTING_MASK,
+        ),
+    ];
+    for (flag, raw) in raw.iter() {
+        assert_eq!(flag.into_raw(), *raw);
+        assert_eq!(NotificationType::from_raw(*raw), *flag);
+    }
+    assert_eq!(
+        (NotificationType::FILE_OPENED | NotificationType::PRE_DELETE).into_raw(),
+        crate::sys::PRJ_NOTIFY_FILE_OPENED | crate::sys::PRJ_NOTIFY_PRE_DELETE
+    );
+
+    for raw in (0..=0xFFFF).chain(0xFFFF_0000..=0xFFFF_FFFF) {
+        assert_eq!(NotificationType::from_raw(raw).into_raw(), raw);
+    }
+}
+
+#[test]
+fn test_notification_type_names() {
+    assert_eq!(NotificationType::NONE.to_string(), "NONE");
+    assert_eq!(
+        NotificationType::USE_EXISTING_MASK.to_string(),
+        "USE_EXISTING_MASK"
+    );
+    assert_eq!(
+        (NotificationType::PRE_DELETE | NotificationType::FILE_OPENED).to_string(),
+        "FILE_OPENED | PRE_DELETE"
+    );
+    assert_eq!(
+        NotificationType::from_raw(0x0001_2002).to_string(),
+        "FILE_OPENED | 0x12000"
+    );
+
+    // every combination of the named flags survives a round trip
+    for raw in 0..=0x1FFF {
+        let mask = NotificationType::from_raw(raw);
+        assert_eq!(mask.to_string().parse::<NotificationType>(), Ok(mask));
+    }
+    for raw in &[0x2000, 0x8000_0001, 0xFFFF_FFFE, 0xFFFF_FFFF] {
+        let mask = NotificationType::from_raw(*raw);
+        assert_eq!(mask.to_string().parse::<NotificationType>(), Ok(mask));
+    }
+
+    assert_eq!(
+        " file_opened|Pre_Delete | 0x2000".parse::<NotificationType>(),
+        Ok(NotificationType::from_raw(0x2012))
+    );
+    assert_eq!(
+        "NONE".parse::<NotificationType>(),
+        Ok(NotificationType::NONE)
+    );
+    for s in &["", "FILE_OPENED |", "FILE_OPEN", "0x", "0xFFFFFFFFF", "1"] {
+        assert!(s.parse::<NotificationType>().is_err(), "{:?}", s);
+    }
+}
+
+#[test]
+fn test_build_keeps_mappings_alive() {
+    use crate::conv::RawWStrExt;
+
+    let builder = OptionBuilder::new()
+        .use_negative_path_cache()
+        .pool_thread_count(4)
+        .add_root_notification(NotificationType::FILE_OPENED)
+        .add_notification(
+            NotificationType::PRE_DELETE | NotificationType::PRE_RENAME,
+            PathBuf::from("sub").join("dir"),
+        );
+    let options = builder.build().unwrap();
+    drop(builder);
+    // moving the guard must not move what the raw struct points to
+    let options = Box::new(options);
+
+    let raw = options.as_raw();
+    assert_eq!(raw.Flags, crate::sys::PRJ_FLAG_USE_NEGATIVE_PATH_CACHE);
+    assert_eq!(raw.PoolThreadCount, 4);
+    assert_eq!(raw.ConcurrentThreadCount, 0);
+    assert_eq!(raw.NotificationMappingsCount, 2);
+
+    let mappings = unsafe {
+        std::slice::from_raw_parts(
+            raw.NotificationMappings,
+            raw.NotificationMappingsCount as usize,
+        )
+    };
+    assert_eq!(
+        mappings[0].NotificationBitMask,
+        crate::sys::PRJ_NOTIFY_FILE_OPENED
+    );
+    assert_eq!(mappings[0].NotificationRoot.to_os(), "");
+    assert_eq!(
+        mappings[1].NotificationBitMask,
+        crate::sys::PRJ_NOTIFY_PRE_DELETE | crate::sys::PRJ_NOTIFY_PRE_RENAME
+    );
+    assert_eq!(mappings[1].NotificationRoot.to_os(), "sub\\dir");
+}
+
+#[test]
+fn test_build_validation() {
+    assert!(OptionBuilder::new().build().is_ok());
+
+    let invalid = [
+        OptionBuilder::new().pool_thread_count(0),
+        OptionBuilder::new().concurrent_thread_count(0),
+        OptionBuilder::new()
+            .add_notification(NotificationType::FILE_OPENED, "a".into())
+            .add_notification(NotificationType::PRE_DELETE, "a".into()),
+        OptionBuilder::new()
+            .add_root_notification(NotificationType::FILE_OPENED)
+            .add_root_notification(NotificationType::PRE_DELETE),
+        OptionBuilder::new().add_notification(
+            NotificationType::FILE_OPENED,
+            std::env::current_dir().unwrap(),
+        ),
+    ];
+    for builder in invalid.iter() {
+        let error = builder.build().err().unwrap();
+        assert_eq!(error.hresult(), Error::invalid_argument().hresult());
+        assert!(error.message().is_some());
+    }
+}
+
+#[test]
+fn test_read_config() {
+    let expected = OptionBuilder::new()
+        .use_negative_path_cache()
+        .pool_thread_count(8)
+        .add_root_notification(NotificationType::FILE_OPENED | NotificationType::PRE_DELETE)
+        .add_notification(NotificationType::NONE, "sub/dir".into());
+
+    let toml = r#"
+        use_negative_path_cache = true
+        pool_thread_count = 8
+
+        [[notifications]]
+        types = "FILE_OPENED | PRE_DELETE"
+
+        [[notifications]]
+        path = "sub/dir"
+        types = "NONE"
+    "#;
+    assert_eq!(OptionBuilder::from_toml(toml), Ok(expected));
+
+    let json = r#"{
+        "use_negative_path_cache": true,
+        "pool_thread_count": 8,
+        "notifications": [
+            { "types": "FILE_OPENED | PRE_DELETE" },
+            { "path": "sub/dir", "types": "NONE" }
+        ]
+    }"#;
+    let from_json = OptionBuilder::from_json(json).unwrap();
+    assert_eq!(from_json, OptionBuilder::from_toml(toml).unwrap());
+
+    assert_eq!(OptionBuilder::from_toml(""), Ok(OptionBuilder::new()));
+    assert_eq!(
+        OptionBuilder::read("options.yaml"),
+        Err(ConfigError::UnknownFormat {
+            path: PathBuf::from("options.yaml")
+        })
+    );
+
+    let dir = crate::testutil::TempDir::new("options");
+    let path = dir.join("options.json");
+    std::fs::write(&path, json).unwrap();
+    assert_eq!(OptionBuilder::read(&path).unwrap(), from_json);
+}
+
+#[test]
+fn test_config_errors() {
+    let line_of = |result: std::result::Result<OptionBuilder, ConfigError>| match result {
+        Err(ConfigError::Invalid { line, message }) => {
+            assert!(!message.is_empty());
+            line
+        }
+        other => panic!("unexpected {:?}", other),
+    };
+
+    // unknown keys
+    assert_eq!(
+        line_of(OptionBuilder::from_toml(
+            "pool_thread_count = 2\npool_threads = 2\n"
+        )),
+        Some(2)
+    );
+    assert_eq!(
+        line_of(OptionBuilder::from_json("{\n\"pool_threads\": 2\n}")),
+        Some(2)
+    );
+    assert_eq!(
+        line_of(OptionBuilder::from_toml(
+            "[[notifications]]\ntypes = \"NONE\"\nroot = \"a\"\n"
+        )),
+        Some(3)
+    );
+
+    // bad flag names
+    let error = OptionBuilder::from_toml("\n[[notifications]]\ntypes = \"FILE_OPENED | OPENED\"\n")
+        .unwrap_err();
+    assert!(error.to_string().contains("OPENED"), "{}", error);
+    assert!(error.to_string().starts_with("invalid options at line 3: "));
+    assert_eq!(
+        line_of(OptionBuilder::from_json(
+            "{\"notifications\": [\n{\"types\": \"BAD\"}\n]}"
+        )),
+        Some(2)
+    );
+
+    // invalid thread counts
+    assert_eq!(
+        line_of(OptionBuilder::from_toml("\nconcurrent_thread_count = 0\n")),
+        Some(2)
+    );
+    assert_eq!(
+        line_of(OptionBuilder::from_toml("pool_thread_count = -1\n")),
+        Some(1)
+    );
+    assert_eq!(
+        line_of(OptionBuilder::from_json("{\"pool_thread_count\": 0}")),
+        Some(1)
+    );
+
+    let error: Error = OptionBuilder::from_toml("x = 1").unwrap_err().into();
+    assert_eq!(error.hresult(), Error::invalid_argument().hresult());
+}
+
+#[test]
+fn test_build_orders_mappings() {
+    use crate::conv::RawWStrExt;
+
+    let mappings = NotificationMappings::new()
+        .add("a/b", NotificationType::PRE_DELETE)
+        .unwrap();
+    let builder = OptionBuilder::new()
+        .add_notification(NotificationType::FILE_OPENED, "./a".into())
+        .add_notification_mappings(&mappings)
+        .add_root_notification(NotificationType::NONE);
+    let options = builder.build().unwrap();
+
+    let raw = options.as_raw();
+    let mappings = unsafe {
+        std::slice::from_raw_parts(
+            raw.NotificationMappings,
+            raw.NotificationMappingsCount as usize,
+        )
+    };
+    assert_eq!(
+        mappings
+            .iter()
+            .map(|m| (m.NotificationRoot.to_os(), m.NotificationBitMask))
+            .collect::<Vec<_>>(),
+        [
+            ("".into(), crate::sys::PRJ_NOTIFY_NONE),
+            ("a".into(), crate::sys::PRJ_NOTIFY_FILE_OPENED),
+            ("a\\b".into(), crate::sys::PRJ_NOTIFY_PRE_DELETE),
+        ]
+    );
+
+    let error = OptionBuilder::new()
+        .add_notification(NotificationType::FILE_OPENED, "a/../b".into())
+        .build()
+        .err()
+        .unwrap();
+    assert_eq!(error.hresult(), Error::invalid_argument().hresult());
 }
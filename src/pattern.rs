@@ -0,0 +1,259 @@
+//! File name matching with the same rules as `PrjFileNameMatch`.
+//!
+//! Expressions may contain the following wildcards, matched against the UTF-16
+//! code units of a name without regard to case:
+//!
+//! * `*` matches zero or more characters.
+//! * `?` matches exactly one character.
+//! * `<` (`DOS_STAR`) matches zero or more characters, but never the final `.`
+//!   of the name.
+//! * `>` (`DOS_QM`) matches any single character other than `.`, or nothing
+//!   when it is at a `.` or at the end of the name.
+//! * `"` (`DOS_DOT`) matches a `.`, or nothing at the end of the name.
+//!
+//! An empty expression matches every name.
+
+use std::ffi::OsStr;
+
+use crate::unicode::{encode_wide, upcase};
+
+const STAR: u16 = b'*' as u16;
+const QM: u16 = b'?' as u16;
+const DOS_STAR: u16 = b'<' as u16;
+const DOS_QM: u16 = b'>' as u16;
+const DOS_DOT: u16 = b'"' as u16;
+const DOT: u16 = b'.' as u16;
+
+/// A compiled search expression that can be matched against many names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    expression: Vec<u16>,
+}
+
+impl Pattern {
+    pub fn new<S: AsRef<OsStr>>(expression: S) -> Self {
+        Self::from_wide(&encode_wide(expression.as_ref()))
+    }
+
+    /// Compiles an expression given as UTF-16 code units.
+    pub fn from_wide(expression: &[u16]) -> Self {
+        Pattern {
+            expression: expression.iter().map(|&unit| upcase(unit)).collect(),
+        }
+    }
+
+    /// Returns a pattern that matches every name.
+    pub fn any() -> Self {
+        Pattern {
+            expression: Vec::new(),
+        }
+    }
+
+    /// Returns `true` if the pattern matches every name.
+    pub fn matches_all(&self) -> bool {
+        self.expression.is_empty() || self.expression == [STAR]
+    }
+
+    pub fn matches<S: AsRef<OsStr>>(&self, name: S) -> bool {
+        self.matches_wide(&encode_wide(name.as_ref()))
+    }
+
+    /// Matches a name given as UTF-16 code units.
+    pub fn matches_wide(&self, name: &[u16]) -> bool {
+        if self.matches_all() {
+            return true;
+        }
+
+        let last_dot = name.iter().rposition(|&unit| unit == DOT);
+        let mut states = vec![false; self.expression.len() + 1];
+        let mut next = vec![false; self.expression.len() + 1];
+        states[0] = true;
+
+        for (position, &unit) in name.iter().enumerate() {
+            self.expand(&mut states, Some(unit));
+
+            let unit = upcase(unit);
+            let is_final_dot = last_dot == Some(position);
+            next.iter_mut().for_each(|state| *state = false);
+
+            for (index, &wildcard) in self.expression.iter().enumerate() {
+                if !states[index] {
+                    continue;
+                }
+
+                match wildcard {
+                    STAR => next[index] = true,
+                    DOS_STAR => {
+                        if !is_final_dot {
+                            next[index] = true;
+                        }
+                    }
+                    QM => next[index + 1] = true,
+                    DOS_QM => {
+                        if unit != DOT {
+                            next[index + 1] = true;
+                        }
+                    }
+                    DOS_DOT => {
+                        if unit == DOT {
+                            next[index + 1] = true;
+                        }
+                    }
+                    literal => {
+                        if literal == unit {
+                            next[index + 1] = true;
+                        }
+                    }
+                }
+            }
+
+            std::mem::swap(&mut states, &mut next);
+            if !states.iter().any(|&state| state) {
+                return false;
+            }
+        }
+
+        self.expand(&mut states, None);
+        states[self.expression.len()]
+    }
+
+    /// Follows the transitions that consume no characters, given the next
+    /// character of the name (`None` at the end of the name). They only ever
+    /// move forward, so a single pass reaches every state.
+    fn expand(&self, states: &mut [bool], next: Option<u16>) {
+        for (index, &wildcard) in self.expression.iter().enumerate() {
+            if !states[index] {
+                continue;
+            }
+
+            let skip = match wildcard {
+                STAR | DOS_STAR => true,
+                DOS_QM => next.is_none() || next == Some(DOT),
+                DOS_DOT => next.is_none(),
+                _ => false,
+            };
+
+            if skip {
+                states[index + 1] = true;
+            }
+        }
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self::any()
+    }
+}
+
+/// Returns `true` if `name` matches `expression`, like `PrjFileNameMatch`.
+pub fn is_match<N: AsRef<OsStr>, E: AsRef<OsStr>>(name: N, expression: E) -> bool {
+    Pattern::new(expression).matches(name)
+}
+
+/// Returns `true` if `expression` contains any wildcard characters, like
+/// `PrjDoesNameContainWildCards`.
+pub fn has_wildcards<E: AsRef<OsStr>>(expression: E) -> bool {
+    encode_wide(expression.as_ref())
+        .iter()
+        .any(|unit| matches!(*unit, STAR | QM | DOS_STAR | DOS_QM | DOS_DOT))
+}
+
+#[test]
+fn test_conformance() {
+    let cases: &[(&str, &str, bool)] = &[
+        // empty expression and plain star match everything
+        ("", "foo.txt", true),
+        ("", "", true),
+        ("*", "foo.txt", true),
+        ("*", "", true),
+        // literals are case-insensitive
+        ("foo.txt", "foo.txt", true),
+        ("FOO.TXT", "foo.txt", true),
+        ("foo.txt", "FoO.TxT", true),
+        ("foo.txt", "foo.tx", false),
+        ("foo.txt", "foo.txtx", false),
+        ("ÄBC", "äbc", true),
+        ("Σ*", "σς", true),
+        ("*Σ", "ας", true),
+        ("straße", "STRASSE", false),
+        // star
+        ("*.txt", "foo.txt", true),
+        ("*.txt", "foo.TXT", true),
+        ("*.txt", ".txt", true),
+        ("*.txt", "foo.txt.bak", false),
+        ("a*b*c", "aXbYc", true),
+        ("a*b*c", "abc", true),
+        ("a*b*c", "aXbY", false),
+        ("*a*", "bab", true),
+        ("**", "x", true),
+        // question mark matches exactly one unit, including a dot
+        ("foo?", "foo1", true),
+        ("foo?", "foo", false),
+        ("foo?", "foo12", false),
+        ("?", ".", true),
+        ("???", "ab", false),
+        // question mark matches a single UTF-16 code unit
+        ("?", "😀", false),
+        ("??", "😀", true),
+        // DOS_STAR never consumes the final dot
+        ("<", "foo", true),
+        ("<", "foo.txt", false),
+        ("<.txt", "foo.txt", true),
+        ("<.txt", "foo.bar.txt", true),
+        ("<.<", "foo.txt", true),
+        ("<.<", "foo", false),
+        ("<txt", "foo.txt", false),
+        ("foo.<", "foo.txt", true),
+        // DOS_QM matches one non-dot character, or nothing at a dot or the end
+        ("foo>>>", "foo", true),
+        ("foo>>>", "foobar", true),
+        ("foo>>>", "foobarx", false),
+        ("foo>.txt", "foo.txt", true),
+        ("foo>.txt", "fooa.txt", true),
+        ("foo>.txt", "fooab.txt", false),
+        (">", ".", false),
+        ("> ", "a ", true),
+        // DOS_DOT matches a dot, or nothing at the end
+        ("foo\"", "foo", true),
+        ("foo\"", "foo.", true),
+        ("foo\"", "foox", false),
+        ("foo\"txt", "foo.txt", true),
+        ("foo\"txt", "footxt", false),
+        ("foo\"*", "foo.bar", true),
+        ("<\"*", "foo.bar", true),
+    ];
+
+    for (expression, name, expected) in cases {
+        assert_eq!(
+            is_match(name, expression),
+            *expected,
+            "matching {:?} against {:?}",
+            name,
+            expression
+        );
+    }
+}
+
+#[test]
+fn test_pattern_reuse() {
+    let pattern = Pattern::new("*.RS");
+    let names = ["lib.rs", "Cargo.toml", "main.rs", "rs"];
+    let matched: Vec<_> = names.iter().filter(|name| pattern.matches(name)).collect();
+
+    assert_eq!(matched, [&"lib.rs", &"main.rs"]);
+    assert!(!pattern.matches_all());
+    assert!(Pattern::new("*").matches_all());
+    assert!(Pattern::default().matches_all());
+}
+
+#[test]
+fn test_has_wildcards() {
+    assert!(has_wildcards("*.txt"));
+    assert!(has_wildcards("a?"));
+    assert!(has_wildcards("<"));
+    assert!(has_wildcards(">"));
+    assert!(has_wildcards("\""));
+    assert!(!has_wildcards("foo.txt"));
+    assert!(!has_wildcards(""));
+}
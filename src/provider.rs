@@ -1,29 +1,44 @@
-use anyhow::{anyhow, Result};
-use std::path::{Path, PathBuf};
+// Without ProjFS the callback plumbing is only reached from tests
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use log::{debug, warn};
+use std::ffi::{OsStr, OsString};
+use std::marker::PhantomPinned;
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::ptr::null_mut;
-use winapi::shared::guiddef::GUID;
-use winapi::um::projectedfslib as prjfs;
-use winapi::{
-    ctypes::c_void,
-    um::winnt::{HRESULT, PCWSTR},
-};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::conv::WStrExt;
-use crate::guid;
+use crate::conv::RawWStrExt;
+use crate::enumeration::{DirectorySource, SessionTable};
+use crate::error::{Error, Result};
+use crate::guid::Guid;
+use crate::handle::{self, VirtualizationHandle};
+use crate::notification::{Notification, Verdict};
+use crate::sys::{self as prjfs, c_void, GUID, HRESULT, PCWSTR, S_OK};
 
-const GUID_FILE: &'static str = ".regfsId";
+#[cfg(any(windows, test))]
+use crate::conv::WStrExt;
+#[cfg(windows)]
+use crate::{
+    error::StartError,
+    handle::AlignedBuffer,
+    root::{MarkDirectory, RootConfig},
+};
+#[cfg(windows)]
+use std::{path::Path, ptr::NonNull};
 
 mod ffi {
-    use winapi::shared::guiddef::GUID;
-    use winapi::shared::ntdef::TRUE;
-    use winapi::um::projectedfslib as prjfs;
-    use winapi::um::winnt::{HRESULT, PCWSTR};
+    use crate::sys::{self as prjfs, GUID, HRESULT, PCWSTR, TRUE};
 
     pub unsafe extern "system" fn start_dir_enum_callback_c(
         data: *const prjfs::PRJ_CALLBACK_DATA,
         enumeration: *const GUID,
     ) -> HRESULT {
-        let provider = (*data).InstanceContext.cast::<super::Provider>();
+        let provider = (*data).InstanceContext.cast::<super::Instance>();
+        let _call = (*provider).calls.enter();
         (*provider).start_dir_enum(&*data, &*enumeration)
     }
 
@@ -31,7 +46,8 @@ mod ffi {
         data: *const prjfs::PRJ_CALLBACK_DATA,
         enumeration: *const GUID,
     ) -> HRESULT {
-        let provider = (*data).InstanceContext.cast::<super::Provider>();
+        let provider = (*data).InstanceContext.cast::<super::Instance>();
+        let _call = (*provider).calls.enter();
         (*provider).end_dir_enum(&*data, &*enumeration)
     }
 
@@ -41,7 +57,8 @@ mod ffi {
         search_expression: PCWSTR,
         dir_entry_buffer_handle: prjfs::PRJ_DIR_ENTRY_BUFFER_HANDLE,
     ) -> HRESULT {
-        let provider = (*data).InstanceContext.cast::<super::Provider>();
+        let provider = (*data).InstanceContext.cast::<super::Instance>();
+        let _call = (*provider).calls.enter();
         (*provider).get_dir_enum(
             &*data,
             &*enumeration,
@@ -53,7 +70,8 @@ mod ffi {
     pub unsafe extern "system" fn get_placeholder_info_callback_c(
         data: *const prjfs::PRJ_CALLBACK_DATA,
     ) -> HRESULT {
-        let provider = (*data).InstanceContext.cast::<super::Provider>();
+        let provider = (*data).InstanceContext.cast::<super::Instance>();
+        let _call = (*provider).calls.enter();
         (*provider).get_placeholder_info(&*data)
     }
 
@@ -62,7 +80,8 @@ mod ffi {
         offset: u64,
         length: u32,
     ) -> HRESULT {
-        let provider = (*data).InstanceContext.cast::<super::Provider>();
+        let provider = (*data).InstanceContext.cast::<super::Instance>();
+        let _call = (*provider).calls.enter();
         (*provider).get_file_data(&*data, offset, length)
     }
 
@@ -73,85 +92,560 @@ mod ffi {
         destination_file_name: PCWSTR,
         parameters: *mut prjfs::PRJ_NOTIFICATION_PARAMETERS,
     ) -> HRESULT {
-        let provider = (*data).InstanceContext.cast::<super::Provider>();
+        let provider = (*data).InstanceContext.cast::<super::Instance>();
+        let _call = (*provider).calls.enter();
         (*provider).notify(
             &*data,
             is_directory == TRUE,
             notification_type,
             destination_file_name,
-            &*parameters,
+            &mut *parameters,
         )
     }
 
     pub unsafe extern "system" fn query_file_name_c(
         data: *const prjfs::PRJ_CALLBACK_DATA,
     ) -> HRESULT {
-        let provider = (*data).InstanceContext.cast::<super::Provider>();
+        let provider = (*data).InstanceContext.cast::<super::Instance>();
+        let _call = (*provider).calls.enter();
         (*provider).query_file_name(&*data)
     }
 
     pub unsafe extern "system" fn cancel_command_c(data: *const prjfs::PRJ_CALLBACK_DATA) {
-        let provider = (*data).InstanceContext.cast::<super::Provider>();
+        let provider = (*data).InstanceContext.cast::<super::Instance>();
+        let _call = (*provider).calls.enter();
         (*provider).cancel_command(&*data);
     }
 }
 
-pub trait ProviderT {
-    /// Returns a mut pointer to a pre-allocated memory space to store
-    /// `prjfs::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT`
-    fn get_context_mut(&mut self) -> Option<*mut prjfs::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT> {
-        None
+bitflags::bitflags! {
+    /// Flags ProjFS attaches to a callback.
+    pub struct CallbackFlags: u32 {
+        /// The directory enumeration must restart from the first entry.
+        const ENUM_RESTART_SCAN = 0x0000_0001;
+        /// Only one entry should be returned for this directory enumeration.
+        const ENUM_RETURN_SINGLE_ENTRY = 0x0000_0002;
+    }
+}
+
+/// Describes the file system operation a callback is invoked for.
+#[derive(Debug, Clone)]
+pub struct CallbackContext {
+    /// Path of the file or directory, relative to the virtualization root.
+    pub file_path: PathBuf,
+    /// ID of the process that triggered the callback, `0` if unknown.
+    pub triggering_process_id: u32,
+    /// Image file name of the triggering process, if ProjFS provided one.
+    pub triggering_process_image: Option<PathBuf>,
+    /// Identifies this invocation, matched by `cancel_command`.
+    pub command_id: i32,
+    /// Identifies the data stream a `get_file_data` request writes to.
+    pub data_stream_id: Guid,
+    /// Modifies the request, such as restarting a directory enumeration.
+    pub flags: CallbackFlags,
+    /// Writes placeholders and file data to the virtualization instance.
+    pub handle: Arc<dyn VirtualizationHandle>,
+}
+
+impl CallbackContext {
+    unsafe fn from_raw(
+        data: &prjfs::PRJ_CALLBACK_DATA,
+        handle: Arc<dyn VirtualizationHandle>,
+    ) -> Self {
+        let triggering_process_image = if data.TriggeringProcessImageFileName.is_null() {
+            None
+        } else {
+            Some(data.TriggeringProcessImageFileName.to_os().into())
+        };
+
+        CallbackContext {
+            file_path: data.FilePathName.to_os().into(),
+            triggering_process_id: data.TriggeringProcessId,
+            triggering_process_image,
+            command_id: data.CommandId,
+            data_stream_id: data.DataStreamId.into(),
+            flags: CallbackFlags::from_bits_truncate(data.Flags),
+            handle,
+        }
+    }
+}
+
+/// 100-nanosecond intervals between 1601-01-01 and 1970-01-01.
+const FILETIME_UNIX_EPOCH: i64 = 116_444_736_000_000_000;
+
+/// Converts `time` to a `FILETIME` value.
+pub(crate) fn to_filetime(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => FILETIME_UNIX_EPOCH + (since.as_nanos() / 100) as i64,
+        Err(e) => FILETIME_UNIX_EPOCH - (e.duration().as_nanos() / 100) as i64,
+    }
+}
+
+/// Basic metadata of a file or directory in the virtualized namespace.
+///
+/// Timestamps are `FILETIME` values (100-nanosecond intervals since January 1,
+/// 1601 UTC); `0` lets ProjFS pick the current time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileBasicInfo {
+    pub is_directory: bool,
+    pub file_size: u64,
+    pub creation_time: i64,
+    pub last_access_time: i64,
+    pub last_write_time: i64,
+    pub change_time: i64,
+    pub file_attributes: u32,
+}
+
+impl FileBasicInfo {
+    pub fn directory() -> Self {
+        FileBasicInfo {
+            is_directory: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn file(size: u64) -> Self {
+        FileBasicInfo {
+            file_size: size,
+            ..Default::default()
+        }
+    }
+
+    fn to_raw(self) -> prjfs::PRJ_FILE_BASIC_INFO {
+        let mut raw = prjfs::PRJ_FILE_BASIC_INFO {
+            IsDirectory: self.is_directory as u8,
+            FileSize: self.file_size as i64,
+            FileAttributes: self.file_attributes,
+            ..Default::default()
+        };
+        unsafe {
+            *raw.CreationTime.QuadPart_mut() = self.creation_time;
+            *raw.LastAccessTime.QuadPart_mut() = self.last_access_time;
+            *raw.LastWriteTime.QuadPart_mut() = self.last_write_time;
+            *raw.ChangeTime.QuadPart_mut() = self.change_time;
+        }
+        raw
+    }
+}
+
+/// Placeholder metadata returned from `ProviderT::get_placeholder_info`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlaceholderInfo {
+    pub basic_info: FileBasicInfo,
+}
+
+impl PlaceholderInfo {
+    pub fn directory() -> Self {
+        FileBasicInfo::directory().into()
+    }
+
+    pub fn file(size: u64) -> Self {
+        FileBasicInfo::file(size).into()
+    }
+
+    fn to_raw(self) -> prjfs::PRJ_PLACEHOLDER_INFO {
+        prjfs::PRJ_PLACEHOLDER_INFO {
+            FileBasicInfo: self.basic_info.to_raw(),
+            ..Default::default()
+        }
     }
+}
+
+impl From<FileBasicInfo> for PlaceholderInfo {
+    fn from(basic_info: FileBasicInfo) -> Self {
+        PlaceholderInfo { basic_info }
+    }
+}
+
+/// Receives directory entries during `ProviderT::get_dir_enum`.
+pub trait DirEntryBuffer {
+    /// Adds an entry to the buffer. Returns `false` if the buffer is full and
+    /// the entry was not added; it should be returned on the next call.
+    fn fill(&mut self, name: &OsStr, info: &FileBasicInfo) -> bool;
+}
+
+struct RawDirEntryBuffer(prjfs::PRJ_DIR_ENTRY_BUFFER_HANDLE);
+
+#[cfg(windows)]
+impl DirEntryBuffer for RawDirEntryBuffer {
+    fn fill(&mut self, name: &OsStr, info: &FileBasicInfo) -> bool {
+        let mut info = info.to_raw();
+        let hr =
+            unsafe { prjfs::PrjFillDirEntryBuffer(name.to_wstr().as_ptr(), &mut info, self.0) };
+        hr == S_OK
+    }
+}
+
+#[cfg(not(windows))]
+impl DirEntryBuffer for RawDirEntryBuffer {
+    fn fill(&mut self, _name: &OsStr, _info: &FileBasicInfo) -> bool {
+        false
+    }
+}
 
-    fn start_dir_enum(
+/// Implemented by the backing store of a virtualization root. Errors are
+/// reported to ProjFS as their `HRESULT`.
+pub trait ProviderT {
+    fn start_dir_enum(&self, context: &CallbackContext, enumeration_id: Guid) -> Result<()>;
+    fn end_dir_enum(&self, context: &CallbackContext, enumeration_id: Guid) -> Result<()>;
+    /// Fills `buffer` with the entries of `context.file_path`. A `None` search
+    /// expression matches every entry.
+    fn get_dir_enum(
         &self,
-        callback_data: &prjfs::PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-    ) -> Result<HRESULT>;
-    fn end_dir_enum(
+        context: &CallbackContext,
+        enumeration_id: Guid,
+        search_expression: Option<&OsStr>,
+        buffer: &mut dyn DirEntryBuffer,
+    ) -> Result<()>;
+    fn get_placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo>;
+    /// Returns exactly `length` bytes of `context.file_path` starting at
+    /// `offset`; ProjFS never requests a range past the end of the file.
+    fn get_file_data(&self, context: &CallbackContext, offset: u64, length: u32)
+        -> Result<Vec<u8>>;
+    /// Answers a notification; `Verdict::Deny` on a `Pre*` notification
+    /// denies the operation.
+    fn notify(
         &self,
-        callback_data: &prjfs::PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-    ) -> Result<HRESULT>;
+        context: &CallbackContext,
+        is_directory: bool,
+        notification: Notification,
+    ) -> Verdict;
+    fn query_file_name(&self, context: &CallbackContext) -> Result<()>;
+    fn cancel_command(&self, context: &CallbackContext);
+}
+
+/// A provider that lists its directories through `DirectorySource` and keeps
+/// its enumerations in a `SessionTable`.
+///
+/// Implementing it implements `ProviderT`, with the enumeration callbacks
+/// served from `sessions`. Notifications are allowed and cancellation is
+/// ignored unless `verdict` and `cancel` are overridden.
+pub trait SourceProvider: DirectorySource {
+    fn sessions(&self) -> &SessionTable;
+    fn placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo>;
+    /// Returns exactly `length` bytes of `context.file_path` starting at
+    /// `offset`.
+    fn file_data(&self, context: &CallbackContext, offset: u64, length: u32) -> Result<Vec<u8>>;
+    /// Succeeds if `context.file_path` exists.
+    fn lookup(&self, context: &CallbackContext) -> Result<()>;
+
+    /// Answers a notification like `ProviderT::notify`.
+    fn verdict(
+        &self,
+        _context: &CallbackContext,
+        _is_directory: bool,
+        _notification: Notification,
+    ) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// Stops the command `context.command_id` like
+    /// `ProviderT::cancel_command`.
+    fn cancel(&self, _context: &CallbackContext) {}
+}
+
+impl<T: SourceProvider> ProviderT for T {
+    fn start_dir_enum(&self, context: &CallbackContext, enumeration_id: Guid) -> Result<()> {
+        self.sessions().start(enumeration_id, &context.file_path);
+        Ok(())
+    }
+
+    fn end_dir_enum(&self, _context: &CallbackContext, enumeration_id: Guid) -> Result<()> {
+        self.sessions().end(enumeration_id);
+        Ok(())
+    }
+
     fn get_dir_enum(
         &self,
-        data: &prjfs::PRJ_CALLBACK_DATA,
-        enumeration: &GUID,
-        search_expression: PCWSTR,
-        dir_entry_buffer_handle: prjfs::PRJ_DIR_ENTRY_BUFFER_HANDLE,
-    ) -> Result<HRESULT>;
-    fn get_placeholder_info(&self, data: &prjfs::PRJ_CALLBACK_DATA) -> Result<HRESULT>;
+        context: &CallbackContext,
+        enumeration_id: Guid,
+        search_expression: Option<&OsStr>,
+        buffer: &mut dyn DirEntryBuffer,
+    ) -> Result<()> {
+        self.sessions()
+            .fill(self, context, enumeration_id, search_expression, buffer)
+    }
+
+    fn get_placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo> {
+        self.placeholder_info(context)
+    }
+
     fn get_file_data(
         &self,
-        data: &prjfs::PRJ_CALLBACK_DATA,
+        context: &CallbackContext,
         offset: u64,
         length: u32,
-    ) -> Result<HRESULT>;
+    ) -> Result<Vec<u8>> {
+        self.file_data(context, offset, length)
+    }
+
     fn notify(
         &self,
-        data: &prjfs::PRJ_CALLBACK_DATA,
+        context: &CallbackContext,
         is_directory: bool,
-        notification_type: prjfs::PRJ_NOTIFICATION,
-        destination_file_name: PCWSTR,
-        parameters: &prjfs::PRJ_NOTIFICATION_PARAMETERS,
-    ) -> Result<HRESULT>;
-    fn query_file_name(&self, data: &prjfs::PRJ_CALLBACK_DATA) -> Result<HRESULT>;
-    fn cancel_command(&self, data: &prjfs::PRJ_CALLBACK_DATA) -> Result<()>;
+        notification: Notification,
+    ) -> Verdict {
+        self.verdict(context, is_directory, notification)
+    }
+
+    fn query_file_name(&self, context: &CallbackContext) -> Result<()> {
+        self.lookup(context)
+    }
+
+    fn cancel_command(&self, context: &CallbackContext) {
+        self.cancel(context)
+    }
 }
 
-pub struct Provider {
+/// Reports the result of a provider callback to ProjFS, logging failures.
+fn into_hresult(callback: &str, result: Result<()>) -> HRESULT {
+    match result {
+        Ok(()) => S_OK,
+        Err(e) if e.is_io_pending() => {
+            debug!("{}: completing asynchronously", callback);
+            e.hresult()
+        }
+        Err(e) => {
+            warn!("{}: provider returned error: {}", callback, e);
+            e.hresult()
+        }
+    }
+}
+
+/// Counts the callbacks currently running so teardown can wait for them.
+#[derive(Debug, Default)]
+struct CallTracker {
+    active: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl CallTracker {
+    fn enter(&self) -> CallGuard<'_> {
+        *self.active.lock().unwrap() += 1;
+        CallGuard(self)
+    }
+
+    /// Blocks until no callback is running.
+    fn wait_idle(&self) {
+        let mut active = self.active.lock().unwrap();
+        while *active > 0 {
+            active = self.idle.wait(active).unwrap();
+        }
+    }
+}
+
+struct CallGuard<'a>(&'a CallTracker);
+
+impl Drop for CallGuard<'_> {
+    fn drop(&mut self) {
+        let mut active = self.0.active.lock().unwrap();
+        *active -= 1;
+        if *active == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+/// The `VirtualizationHandle` of a ProjFS virtualization instance.
+#[derive(Debug, Default)]
+struct OsHandle {
+    /// The `PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT`, null while virtualization
+    /// is not running.
+    context: AtomicPtr<c_void>,
+}
+
+impl OsHandle {
+    fn attach(&self, context: prjfs::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) {
+        let _ = self.context.compare_exchange(
+            null_mut(),
+            context.cast(),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    fn detach(&self) -> prjfs::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT {
+        self.context.swap(null_mut(), Ordering::AcqRel).cast()
+    }
+
+    fn context(&self) -> Result<prjfs::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT> {
+        let context = self.context.load(Ordering::Acquire);
+        if context.is_null() {
+            return Err(Error::invalid_argument().with_message("virtualization is not running"));
+        }
+        Ok(context.cast())
+    }
+}
+
+#[cfg(windows)]
+unsafe fn free_os_buffer(ptr: NonNull<u8>, _len: usize) {
+    prjfs::PrjFreeAlignedBuffer(ptr.as_ptr().cast());
+}
+
+#[cfg(windows)]
+impl VirtualizationHandle for OsHandle {
+    fn write_placeholder_info(&self, path: &Path, info: &PlaceholderInfo) -> Result<()> {
+        let info = info.to_raw();
+        let hr = unsafe {
+            prjfs::PrjWritePlaceholderInfo(
+                self.context()?,
+                path.to_wstr().as_ptr(),
+                &info,
+                std::mem::size_of_val(&info) as u32,
+            )
+        };
+        if hr < 0 {
+            return Err(Error::from_hresult(hr));
+        }
+        Ok(())
+    }
+
+    fn write_file_data(
+        &self,
+        data_stream_id: Guid,
+        buffer: &AlignedBuffer,
+        offset: u64,
+    ) -> Result<()> {
+        let hr = unsafe {
+            prjfs::PrjWriteFileData(
+                self.context()?,
+                &data_stream_id.into(),
+                buffer.as_ptr().cast(),
+                offset,
+                buffer.len() as u32,
+            )
+        };
+        if hr < 0 {
+            return Err(Error::from_hresult(hr));
+        }
+        Ok(())
+    }
+
+    fn allocate_aligned_buffer(&self, size: usize) -> Result<AlignedBuffer> {
+        let ptr = unsafe { prjfs::PrjAllocateAlignedBuffer(self.context()?, size) };
+        match NonNull::new(ptr.cast::<u8>()) {
+            Some(ptr) => Ok(unsafe { AlignedBuffer::from_raw_parts(ptr, size, free_os_buffer) }),
+            None => Err(Error::out_of_memory()),
+        }
+    }
+}
+
+/// The target of the ProjFS callbacks, passed as the instance context. It is
+/// pinned on the heap so its address stays valid however `Provider` moves.
+struct Instance {
     inner: Box<dyn ProviderT>,
+    handle: Arc<dyn VirtualizationHandle>,
+    /// Set when `handle` is an `OsHandle`, so callbacks arriving before
+    /// `PrjStartVirtualizing` returns can attach the context.
+    os_handle: Option<Arc<OsHandle>>,
+    calls: CallTracker,
+    _pin: PhantomPinned,
+}
+
+impl Instance {
+    fn new(
+        inner: Box<dyn ProviderT>,
+        handle: Arc<dyn VirtualizationHandle>,
+        os_handle: Option<Arc<OsHandle>>,
+    ) -> Pin<Box<Instance>> {
+        Box::pin(Instance {
+            inner,
+            handle,
+            os_handle,
+            calls: CallTracker::default(),
+            _pin: PhantomPinned,
+        })
+    }
+
+    fn as_context(self: Pin<&Self>) -> *const c_void {
+        (self.get_ref() as *const Instance).cast()
+    }
+
+    unsafe fn callback_context(&self, data: &prjfs::PRJ_CALLBACK_DATA) -> CallbackContext {
+        if let Some(os_handle) = &self.os_handle {
+            os_handle.attach(data.NamespaceVirtualizationContext);
+        }
+        CallbackContext::from_raw(data, self.handle.clone())
+    }
+}
+
+/// The OS side of a running virtualization instance. Kept behind a trait so
+/// the teardown order of `Provider` can be checked without ProjFS.
+trait Virtualization {
+    fn stop_virtualizing(&mut self);
+}
+
+#[cfg(windows)]
+struct OsVirtualization {
+    handle: Arc<OsHandle>,
+}
+
+#[cfg(windows)]
+impl Virtualization for OsVirtualization {
+    fn stop_virtualizing(&mut self) {
+        let context = self.handle.detach();
+        if !context.is_null() {
+            unsafe { prjfs::PrjStopVirtualizing(context) };
+        }
+    }
+}
+
+#[cfg(windows)]
+struct OsMarkDirectory;
+
+#[cfg(windows)]
+impl MarkDirectory for OsMarkDirectory {
+    fn mark_directory_as_placeholder(&self, root: &Path, instance_id: Guid) -> Result<()> {
+        let hr = unsafe {
+            prjfs::PrjMarkDirectoryAsPlaceholder(
+                root.to_wstr().as_ptr(),
+                null_mut(),
+                null_mut(),
+                &instance_id.into(),
+            )
+        };
+        if hr < 0 {
+            return Err(Error::from_hresult(hr));
+        }
+        Ok(())
+    }
+}
+
+/// A running virtualization instance. Virtualization stops when the provider
+/// is dropped or `stop` is called.
+pub struct Provider {
+    instance: Pin<Box<Instance>>,
+    /// Read by ProjFS until virtualization stops.
+    callbacks: Option<Box<prjfs::PRJ_CALLBACKS>>,
+    /// `None` once virtualization has stopped.
+    virtualization: Option<Box<dyn Virtualization>>,
 }
 
 impl Provider {
+    #[cfg(windows)]
     pub fn new(
         root_path: PathBuf,
+        root: RootConfig,
         options: crate::option::OptionBuilder,
         inner: Box<dyn ProviderT>,
-    ) -> Result<Provider> {
-        Self::ensure_virtualization_root(&root_path)?;
+    ) -> std::result::Result<Provider, StartError> {
+        let options = match options.build() {
+            Ok(options) => options,
+            Err(source) => {
+                return Err(StartError::Options {
+                    root: root_path,
+                    source,
+                })
+            }
+        };
+        if let Err(source) = root.prepare(&root_path, &OsMarkDirectory) {
+            return Err(StartError::Root {
+                root: root_path,
+                source,
+            });
+        }
 
-        let callbacks = prjfs::PRJ_CALLBACKS {
+        let callbacks = Box::new(prjfs::PRJ_CALLBACKS {
             StartDirectoryEnumerationCallback: Some(ffi::start_dir_enum_callback_c),
             EndDirectoryEnumerationCallback: Some(ffi::end_dir_enum_callback_c),
             GetDirectoryEnumerationCallback: Some(ffi::get_dir_enum_callback_c),
@@ -160,143 +654,552 @@ impl Provider {
             QueryFileNameCallback: Some(ffi::query_file_name_c),
             CancelCommandCallback: Some(ffi::cancel_command_c),
             NotificationCallback: Some(ffi::notification_callback_c),
-        };
+        });
 
-        let mut provider = Provider { inner };
-        let ctx = provider
-            .inner
-            .get_context_mut()
-            .unwrap_or(&mut std::ptr::null_mut());
-        let options = options.build();
+        let handle = Arc::new(OsHandle::default());
+        let instance = Instance::new(inner, handle.clone(), Some(handle.clone()));
+        let mut context = null_mut();
 
-        unsafe {
-            // TODO: check HRESULT
+        let hr = unsafe {
             prjfs::PrjStartVirtualizing(
-                root_path.into_os_string().to_wstr().as_ptr(),
-                Box::into_raw(Box::new(callbacks)),
-                (&provider as *const Provider) as *const c_void,
-                &options,
-                ctx,
-            );
+                root_path.to_wstr().as_ptr(),
+                &*callbacks,
+                instance.as_ref().as_context(),
+                options.as_raw(),
+                &mut context,
+            )
+        };
+        if hr < 0 {
+            return Err(StartError::virtualize(root_path, hr));
         }
+        handle.attach(context);
+
+        Ok(Provider {
+            instance,
+            callbacks: Some(callbacks),
+            virtualization: Some(Box::new(OsVirtualization { handle })),
+        })
+    }
 
-        Ok(provider)
+    /// Returns the provider serving the callbacks.
+    pub fn provider(&self) -> &dyn ProviderT {
+        &*self.instance.inner
     }
 
-    fn ensure_virtualization_root<T: AsRef<Path>>(root_path: T) -> Result<()> {
-        let root_path = root_path.as_ref();
-        let guid_file = root_path.join(GUID_FILE);
+    /// Stops virtualization and waits for the callbacks still running to
+    /// return. Dropping the provider does the same.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
 
-        if root_path.exists() && root_path.is_dir() {
-            if !root_path.is_dir() {
-                return Err(anyhow!(format!("{:?} is not a directory", root_path)));
-            }
-            // virtualization root is present, attempts to read guid
-            let guid = std::fs::read(&guid_file)?;
-            guid::guid_from_bytes(guid).map_err(|_| anyhow!("unable to read GUID"))?;
-            Ok(())
-        } else {
-            let guid = guid::create_guid();
-            std::fs::create_dir(&root_path)?;
-            std::fs::write(&guid_file, guid::guid_to_bytes(&guid))?;
-            let hr = unsafe {
-                prjfs::PrjMarkDirectoryAsPlaceholder(
-                    root_path.clone().to_wstr().as_ptr(),
-                    null_mut(),
-                    null_mut(),
-                    &guid,
-                )
-            };
-            if hr < 0 {
-                // failed, clean up
-                let _ = std::fs::remove_file(&guid_file);
-                let _ = std::fs::remove_dir(&root_path);
-                return Err(anyhow!(format!("HRESULT: {}", hr)));
-            }
-            Ok(())
+    fn shutdown(&mut self) {
+        if let Some(mut virtualization) = self.virtualization.take() {
+            virtualization.stop_virtualizing();
+            // ProjFS starts no new callbacks once stopped, but the ones
+            // already running still use the instance and the callback table
+            self.instance.calls.wait_idle();
+            self.callbacks = None;
         }
     }
+}
 
-    pub fn start_dir_enum(
-        &self,
-        callback_data: &prjfs::PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-    ) -> HRESULT {
-        self.inner
-            .start_dir_enum(callback_data, enumeration_id)
-            .unwrap_or(winapi::shared::winerror::S_FALSE)
+impl Drop for Provider {
+    fn drop(&mut self) {
+        self.shutdown();
     }
+}
 
-    pub fn end_dir_enum(
-        &self,
-        callback_data: &prjfs::PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-    ) -> HRESULT {
-        self.inner
-            .end_dir_enum(callback_data, enumeration_id)
-            .unwrap_or(winapi::shared::winerror::S_FALSE)
+impl Instance {
+    fn start_dir_enum(&self, data: &prjfs::PRJ_CALLBACK_DATA, enumeration_id: &GUID) -> HRESULT {
+        let context = unsafe { self.callback_context(data) };
+        into_hresult(
+            "start_dir_enum",
+            self.inner
+                .start_dir_enum(&context, (*enumeration_id).into()),
+        )
     }
 
-    pub fn get_dir_enum(
+    fn end_dir_enum(&self, data: &prjfs::PRJ_CALLBACK_DATA, enumeration_id: &GUID) -> HRESULT {
+        let context = unsafe { self.callback_context(data) };
+        into_hresult(
+            "end_dir_enum",
+            self.inner.end_dir_enum(&context, (*enumeration_id).into()),
+        )
+    }
+
+    fn get_dir_enum(
         &self,
         data: &prjfs::PRJ_CALLBACK_DATA,
-        enumeration: &GUID,
+        enumeration_id: &GUID,
         search_expression: PCWSTR,
         dir_entry_buffer_handle: prjfs::PRJ_DIR_ENTRY_BUFFER_HANDLE,
     ) -> HRESULT {
-        self.inner
-            .get_dir_enum(
-                data,
-                enumeration,
-                search_expression,
-                dir_entry_buffer_handle,
-            )
-            .unwrap_or(winapi::shared::winerror::S_FALSE)
+        let context = unsafe { self.callback_context(data) };
+        let search_expression: Option<OsString> = if search_expression.is_null() {
+            None
+        } else {
+            Some(search_expression.to_os())
+        };
+        let mut buffer = RawDirEntryBuffer(dir_entry_buffer_handle);
+
+        into_hresult(
+            "get_dir_enum",
+            self.inner.get_dir_enum(
+                &context,
+                (*enumeration_id).into(),
+                search_expression.as_deref(),
+                &mut buffer,
+            ),
+        )
     }
 
-    pub fn get_placeholder_info(&self, data: &prjfs::PRJ_CALLBACK_DATA) -> HRESULT {
-        self.inner
-            .get_placeholder_info(data)
-            .unwrap_or(winapi::shared::winerror::S_FALSE)
+    fn get_placeholder_info(&self, data: &prjfs::PRJ_CALLBACK_DATA) -> HRESULT {
+        let context = unsafe { self.callback_context(data) };
+        let result = self.inner.get_placeholder_info(&context).and_then(|info| {
+            context
+                .handle
+                .write_placeholder_info(&context.file_path, &info)
+        });
+
+        into_hresult("get_placeholder_info", result)
     }
 
-    pub fn get_file_data(
-        &self,
-        data: &prjfs::PRJ_CALLBACK_DATA,
-        offset: u64,
-        length: u32,
-    ) -> HRESULT {
-        self.inner
-            .get_file_data(data, offset, length)
-            .unwrap_or(winapi::shared::winerror::S_FALSE)
+    fn get_file_data(&self, data: &prjfs::PRJ_CALLBACK_DATA, offset: u64, length: u32) -> HRESULT {
+        let context = unsafe { self.callback_context(data) };
+        let result = self
+            .inner
+            .get_file_data(&context, offset, length)
+            .and_then(|bytes| {
+                if bytes.len() != length as usize {
+                    return Err(Error::other(format!(
+                        "requested {} bytes at offset {}, got {}",
+                        length,
+                        offset,
+                        bytes.len()
+                    )));
+                }
+                handle::write_bytes(&*context.handle, context.data_stream_id, offset, &bytes)
+            });
+
+        into_hresult("get_file_data", result)
     }
 
-    pub fn notify(
+    fn notify(
         &self,
         data: &prjfs::PRJ_CALLBACK_DATA,
         is_directory: bool,
         notification_type: prjfs::PRJ_NOTIFICATION,
         destination_file_name: PCWSTR,
-        parameters: &prjfs::PRJ_NOTIFICATION_PARAMETERS,
+        parameters: &mut prjfs::PRJ_NOTIFICATION_PARAMETERS,
     ) -> HRESULT {
-        self.inner
-            .notify(
-                data,
-                is_directory,
-                notification_type,
-                destination_file_name,
-                parameters,
-            )
-            .unwrap_or(winapi::shared::winerror::S_FALSE)
+        let context = unsafe { self.callback_context(data) };
+        let destination = if destination_file_name.is_null() {
+            PathBuf::new()
+        } else {
+            destination_file_name.to_os().into()
+        };
+
+        let notification = match Notification::from_raw(notification_type, destination, parameters)
+        {
+            Ok(notification) => notification,
+            Err(e) => {
+                // denying an operation we do not understand would break it
+                warn!("notify: {}", e);
+                return S_OK;
+            }
+        };
+        let kind = notification.kind();
+        let verdict = self.inner.notify(&context, is_directory, notification);
+        into_hresult("notify", verdict.apply(kind, parameters))
+    }
+
+    fn query_file_name(&self, data: &prjfs::PRJ_CALLBACK_DATA) -> HRESULT {
+        let context = unsafe { self.callback_context(data) };
+        into_hresult("query_file_name", self.inner.query_file_name(&context))
     }
 
-    pub fn query_file_name(&self, data: &prjfs::PRJ_CALLBACK_DATA) -> HRESULT {
-        self.inner
-            .query_file_name(data)
-            .unwrap_or(winapi::shared::winerror::S_FALSE)
+    fn cancel_command(&self, data: &prjfs::PRJ_CALLBACK_DATA) {
+        let context = unsafe { self.callback_context(data) };
+        self.inner.cancel_command(&context);
+    }
+}
+
+#[cfg(test)]
+use crate::handle::{RecordingHandle, Write};
+
+#[cfg(test)]
+type Gate = (std::sync::mpsc::Sender<()>, std::sync::mpsc::Receiver<()>);
+
+#[cfg(test)]
+struct RecordingProvider {
+    calls: Arc<Mutex<Vec<String>>>,
+    /// If set, `start_dir_enum` signals the sender once it runs, then blocks
+    /// until the receiver gets a message.
+    gate: Option<Mutex<Gate>>,
+}
+
+#[cfg(test)]
+impl RecordingProvider {
+    fn record(&self, callback: &str, context: &CallbackContext) {
+        self.calls.lock().unwrap().push(format!(
+            "{} {} {}",
+            callback,
+            context.file_path.display(),
+            context.command_id
+        ));
+    }
+}
+
+#[cfg(test)]
+impl ProviderT for RecordingProvider {
+    fn start_dir_enum(&self, context: &CallbackContext, _enumeration_id: Guid) -> Result<()> {
+        self.record("start_dir_enum", context);
+        if let Some(gate) = &self.gate {
+            let (started, release) = &*gate.lock().unwrap();
+            started.send(()).unwrap();
+            release.recv().unwrap();
+            self.calls
+                .lock()
+                .unwrap()
+                .push("start_dir_enum returned".into());
+        }
+        Ok(())
     }
 
-    pub fn cancel_command(&self, data: &prjfs::PRJ_CALLBACK_DATA) {
-        let _ = self.inner.cancel_command(data);
+    fn end_dir_enum(&self, context: &CallbackContext, _enumeration_id: Guid) -> Result<()> {
+        self.record("end_dir_enum", context);
+        Ok(())
     }
+
+    fn get_dir_enum(
+        &self,
+        context: &CallbackContext,
+        _enumeration_id: Guid,
+        search_expression: Option<&OsStr>,
+        _buffer: &mut dyn DirEntryBuffer,
+    ) -> Result<()> {
+        self.record("get_dir_enum", context);
+        assert_eq!(search_expression, Some(OsStr::new("*.txt")));
+        Ok(())
+    }
+
+    fn get_placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo> {
+        self.record("get_placeholder_info", context);
+        Ok(PlaceholderInfo::file(9))
+    }
+
+    fn get_file_data(
+        &self,
+        context: &CallbackContext,
+        offset: u64,
+        length: u32,
+    ) -> Result<Vec<u8>> {
+        self.record("get_file_data", context);
+        let data = b"file data";
+        let start = (offset as usize).min(data.len());
+        let end = (start + length as usize).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn notify(
+        &self,
+        context: &CallbackContext,
+        _is_directory: bool,
+        notification: Notification,
+    ) -> Verdict {
+        self.record("notify", context);
+        assert_eq!(
+            notification,
+            Notification::PreRename {
+                destination: PathBuf::from("renamed")
+            }
+        );
+        Verdict::Deny(Error::access_denied())
+    }
+
+    fn query_file_name(&self, context: &CallbackContext) -> Result<()> {
+        self.record("query_file_name", context);
+        Err(Error::not_found())
+    }
+
+    fn cancel_command(&self, context: &CallbackContext) {
+        self.record("cancel_command", context);
+    }
+}
+
+#[cfg(test)]
+struct FakeVirtualization {
+    calls: Arc<Mutex<Vec<String>>>,
+    stopped: std::sync::mpsc::Sender<()>,
+}
+
+#[cfg(test)]
+impl Virtualization for FakeVirtualization {
+    fn stop_virtualizing(&mut self) {
+        self.calls.lock().unwrap().push("stop_virtualizing".into());
+        let _ = self.stopped.send(());
+    }
+}
+
+#[cfg(test)]
+fn test_callback_data(
+    instance_context: *const c_void,
+    file_path: &crate::conv::WStr,
+) -> prjfs::PRJ_CALLBACK_DATA {
+    prjfs::PRJ_CALLBACK_DATA {
+        Size: std::mem::size_of::<prjfs::PRJ_CALLBACK_DATA>() as u32,
+        CommandId: 7,
+        FilePathName: file_path.as_ptr(),
+        InstanceContext: instance_context as *mut c_void,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_trampolines_reach_moved_instance() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let handle = Arc::new(RecordingHandle::new());
+    let instance = Instance::new(
+        Box::new(RecordingProvider {
+            calls: calls.clone(),
+            gate: None,
+        }),
+        handle.clone(),
+        None,
+    );
+    let file_path = "file".to_wstr();
+    let data = test_callback_data(instance.as_ref().as_context(), &file_path);
+
+    // the context must stay valid when the owner of the instance moves
+    let owners = vec![instance];
+
+    let enumeration_id: GUID = Guid::default().into();
+    let search_expression = "*.txt".to_wstr();
+    let destination = "renamed".to_wstr();
+    let mut parameters = prjfs::PRJ_NOTIFICATION_PARAMETERS::default();
+
+    unsafe {
+        assert_eq!(ffi::start_dir_enum_callback_c(&data, &enumeration_id), S_OK);
+        assert_eq!(
+            ffi::get_dir_enum_callback_c(
+                &data,
+                &enumeration_id,
+                search_expression.as_ptr(),
+                null_mut()
+            ),
+            S_OK
+        );
+        assert_eq!(ffi::end_dir_enum_callback_c(&data, &enumeration_id), S_OK);
+        assert_eq!(ffi::get_placeholder_info_callback_c(&data), S_OK);
+        assert_eq!(ffi::get_file_data_callback_c(&data, 5, 4), S_OK);
+        assert_eq!(
+            ffi::get_file_data_callback_c(&data, 0, 16),
+            Error::other("").hresult()
+        );
+        assert_eq!(
+            ffi::notification_callback_c(
+                &data,
+                0,
+                prjfs::PRJ_NOTIFICATION_PRE_RENAME,
+                destination.as_ptr(),
+                &mut parameters
+            ),
+            Error::access_denied().hresult()
+        );
+        assert_eq!(ffi::query_file_name_c(&data), Error::not_found().hresult());
+        ffi::cancel_command_c(&data);
+    }
+
+    assert_eq!(
+        *calls.lock().unwrap(),
+        [
+            "start_dir_enum file 7",
+            "get_dir_enum file 7",
+            "end_dir_enum file 7",
+            "get_placeholder_info file 7",
+            "get_file_data file 7",
+            "get_file_data file 7",
+            "notify file 7",
+            "query_file_name file 7",
+            "cancel_command file 7",
+        ]
+    );
+    // a short read is rejected before anything is written
+    assert_eq!(
+        handle.take_writes(),
+        [
+            Write::PlaceholderInfo {
+                path: PathBuf::from("file"),
+                info: PlaceholderInfo::file(9),
+            },
+            Write::FileData {
+                data_stream_id: Guid::default(),
+                offset: 5,
+                data: b"data".to_vec(),
+            },
+        ]
+    );
+    drop(owners);
+}
+
+#[test]
+fn test_stop_waits_for_running_callbacks() {
+    use std::sync::mpsc;
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel();
+    let provider = Provider {
+        instance: Instance::new(
+            Box::new(RecordingProvider {
+                calls: calls.clone(),
+                gate: Some(Mutex::new((started_tx, release_rx))),
+            }),
+            Arc::new(RecordingHandle::new()),
+            None,
+        ),
+        callbacks: Some(Box::default()),
+        virtualization: Some(Box::new(FakeVirtualization {
+            calls: calls.clone(),
+            stopped: release_tx,
+        })),
+    };
+
+    let instance_context = provider.instance.as_ref().as_context() as usize;
+    let callback = std::thread::spawn(move || {
+        let file_path = "file".to_wstr();
+        let data = test_callback_data(instance_context as *const c_void, &file_path);
+        let enumeration_id: GUID = Guid::default().into();
+        unsafe { ffi::start_dir_enum_callback_c(&data, &enumeration_id) }
+    });
+
+    started_rx.recv().unwrap();
+    provider.stop();
+    calls.lock().unwrap().push("stopped".into());
+
+    assert_eq!(callback.join().unwrap(), S_OK);
+    assert_eq!(
+        *calls.lock().unwrap(),
+        [
+            "start_dir_enum file 7",
+            "stop_virtualizing",
+            "start_dir_enum returned",
+            "stopped",
+        ]
+    );
+}
+
+#[test]
+fn test_drop_stops_virtualizing_once() {
+    use std::sync::mpsc;
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+    let mut provider = Provider {
+        instance: Instance::new(
+            Box::new(RecordingProvider {
+                calls: calls.clone(),
+                gate: None,
+            }),
+            Arc::new(RecordingHandle::new()),
+            None,
+        ),
+        callbacks: Some(Box::default()),
+        virtualization: Some(Box::new(FakeVirtualization {
+            calls: calls.clone(),
+            stopped: stopped_tx,
+        })),
+    };
+
+    provider.shutdown();
+    assert!(provider.callbacks.is_none());
+    drop(provider);
+
+    assert_eq!(*calls.lock().unwrap(), ["stop_virtualizing"]);
+    assert_eq!(stopped_rx.try_iter().count(), 1);
+}
+
+#[cfg(windows)]
+#[test]
+fn test_os_handle_requires_running_virtualization() {
+    let handle = OsHandle::default();
+    let error = handle.allocate_aligned_buffer(16).unwrap_err();
+    assert_eq!(error.hresult(), Error::invalid_argument().hresult());
+    assert!(handle.detach().is_null());
+}
+
+/// Serves an empty tree and keeps every file from being deleted.
+#[cfg(test)]
+#[derive(Default)]
+struct UndeletableSource {
+    sessions: SessionTable,
+    cancelled: Mutex<Vec<i32>>,
+}
+
+#[cfg(test)]
+impl DirectorySource for UndeletableSource {
+    fn read_dir(&self, _path: &std::path::Path) -> Result<Vec<crate::enumeration::DirEntry>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+impl SourceProvider for UndeletableSource {
+    fn sessions(&self) -> &SessionTable {
+        &self.sessions
+    }
+
+    fn placeholder_info(&self, _context: &CallbackContext) -> Result<PlaceholderInfo> {
+        Err(Error::not_found())
+    }
+
+    fn file_data(&self, _context: &CallbackContext, _offset: u64, _length: u32) -> Result<Vec<u8>> {
+        Err(Error::not_found())
+    }
+
+    fn lookup(&self, _context: &CallbackContext) -> Result<()> {
+        Err(Error::not_found())
+    }
+
+    fn verdict(
+        &self,
+        _context: &CallbackContext,
+        _is_directory: bool,
+        notification: Notification,
+    ) -> Verdict {
+        match notification {
+            Notification::PreDelete => Verdict::Deny(Error::access_denied()),
+            _ => Verdict::Allow,
+        }
+    }
+
+    fn cancel(&self, context: &CallbackContext) {
+        self.cancelled.lock().unwrap().push(context.command_id);
+    }
+}
+
+#[test]
+fn test_source_provider_overrides() {
+    let host = crate::sim::SimHost::new(UndeletableSource::default());
+
+    assert!(host.read_dir("", None).unwrap().is_empty());
+    let denied = host.notify("file", false, Notification::PreDelete);
+    assert_eq!(denied.unwrap_err(), Error::access_denied());
+    assert!(host.notify("file", false, Notification::FileOpened).is_ok());
+
+    host.cancel_command("file", 7);
+    assert_eq!(*host.provider().cancelled.lock().unwrap(), [7]);
+}
+
+#[test]
+fn test_to_filetime() {
+    assert_eq!(to_filetime(UNIX_EPOCH), FILETIME_UNIX_EPOCH);
+    assert_eq!(
+        to_filetime(UNIX_EPOCH + std::time::Duration::from_secs(1)),
+        FILETIME_UNIX_EPOCH + 10_000_000
+    );
+    assert_eq!(
+        to_filetime(UNIX_EPOCH - std::time::Duration::from_micros(1)),
+        FILETIME_UNIX_EPOCH - 10
+    );
 }
@@ -0,0 +1,755 @@
+//! The marker file identifying a virtualization root.
+//!
+//! A virtualization root holds a marker file, a small JSON document recording
+//! the instance ID the root was marked with and the provider that owns it:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "instance_id": "{6B29FC40-CA47-1067-B31D-00DD010662DA}",
+//!   "provider_name": "regfs",
+//!   "provider_version": "0.1.0",
+//!   "created": 1700000000,
+//!   "metadata": {}
+//! }
+//! ```
+//!
+//! `RootConfig` describes the marker a provider expects and reads, validates
+//! and writes it. `RootConfig::prepare` creates, adopts or reopens the root
+//! according to its `RootMode`.
+//!
+//! Earlier markers were binary: the bare 16 GUID bytes big-endian, or the
+//! `RootMarker` format, a small header followed by the GUID:
+//!
+//! ```text
+//! offset  size  contents
+//! 0       4     magic, "PJID"
+//! 4       1     format version, 1
+//! 5       1     byte order of Data1..Data3, b'L' or b'B'
+//! 6       16    instance ID
+//! ```
+//!
+//! `RootConfig::open` reads both and rewrites them as JSON, adopting the root
+//! for the opening provider.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::guid::Guid;
+
+/// The default name of the marker file inside the virtualization root.
+pub const MARKER_FILE: &str = ".prjfs-root.json";
+
+/// The schema version of the JSON marker written by this crate.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"PJID";
+const HEADER_LEN: usize = 6;
+const GUID_LEN: usize = 16;
+
+/// The byte order of the `Data1`, `Data2` and `Data3` fields of a stored
+/// GUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            b'L' => Some(ByteOrder::LittleEndian),
+            b'B' => Some(ByteOrder::BigEndian),
+            _ => None,
+        }
+    }
+}
+
+/// A binary root marker, read only to migrate it to `RootMetadata`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RootMarker {
+    instance_id: Guid,
+}
+
+impl RootMarker {
+    /// The only version of the headed binary format.
+    const VERSION: u8 = 1;
+
+    pub(crate) fn instance_id(&self) -> Guid {
+        self.instance_id
+    }
+
+    /// Parses the headerless big-endian format or the headed format.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let invalid = |message: &str| {
+            Error::invalid_argument().with_message(format!("invalid root marker: {}", message))
+        };
+
+        let mut guid = [0u8; GUID_LEN];
+        if bytes.len() == GUID_LEN {
+            guid.copy_from_slice(bytes);
+            return Ok(RootMarker {
+                instance_id: Guid::from_bytes_be(guid),
+            });
+        }
+
+        if bytes.len() != HEADER_LEN + GUID_LEN || &bytes[..4] != MAGIC {
+            return Err(invalid("unrecognized format"));
+        }
+        if bytes[4] != Self::VERSION {
+            return Err(invalid(&format!("unsupported version {}", bytes[4])));
+        }
+        let byte_order = ByteOrder::from_tag(bytes[5]).ok_or_else(|| invalid("bad byte order"))?;
+
+        guid.copy_from_slice(&bytes[HEADER_LEN..]);
+        let instance_id = match byte_order {
+            ByteOrder::LittleEndian => Guid::from_bytes_le(guid),
+            ByteOrder::BigEndian => Guid::from_bytes_be(guid),
+        };
+        Ok(RootMarker { instance_id })
+    }
+}
+
+/// An error reading or validating a root marker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkerError {
+    /// The marker could not be read or written.
+    Io(Error),
+    /// The marker is not a valid document.
+    Corrupt { reason: String },
+    /// The marker was written by a newer schema.
+    UnsupportedVersion { found: u32 },
+    /// The root belongs to a different provider.
+    ForeignProvider { expected: String, found: String },
+}
+
+impl fmt::Display for MarkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarkerError::Io(error) => write!(f, "unable to access the root marker: {}", error),
+            MarkerError::Corrupt { reason } => write!(f, "corrupt root marker: {}", reason),
+            MarkerError::UnsupportedVersion { found } => write!(
+                f,
+                "unsupported root marker schema version {} (expected at most {})",
+                found, SCHEMA_VERSION
+            ),
+            MarkerError::ForeignProvider { expected, found } => write!(
+                f,
+                "the root belongs to provider {:?}, not {:?}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MarkerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MarkerError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MarkerError {
+    fn from(error: std::io::Error) -> Self {
+        MarkerError::Io(error.into())
+    }
+}
+
+impl From<MarkerError> for Error {
+    fn from(error: MarkerError) -> Self {
+        match error {
+            MarkerError::Io(error) => error,
+            error => Error::invalid_argument().with_message(error.to_string()),
+        }
+    }
+}
+
+/// The contents of a JSON root marker.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootMetadata {
+    pub schema_version: u32,
+    pub instance_id: Guid,
+    pub provider_name: String,
+    pub provider_version: String,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// Free-form values owned by the provider.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl RootMetadata {
+    pub fn created_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.created)
+    }
+
+    pub fn from_json(json: &str) -> std::result::Result<Self, MarkerError> {
+        let metadata: RootMetadata =
+            serde_json::from_str(json).map_err(|e| MarkerError::Corrupt {
+                reason: e.to_string(),
+            })?;
+        if metadata.schema_version > SCHEMA_VERSION {
+            return Err(MarkerError::UnsupportedVersion {
+                found: metadata.schema_version,
+            });
+        }
+        Ok(metadata)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("root metadata is always serializable")
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> std::result::Result<Self, MarkerError> {
+        let bytes = std::fs::read(path)?;
+        let json = std::str::from_utf8(&bytes).map_err(|e| MarkerError::Corrupt {
+            reason: e.to_string(),
+        })?;
+        Self::from_json(json)
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> std::result::Result<(), MarkerError> {
+        std::fs::write(path, self.to_json())?;
+        Ok(())
+    }
+}
+
+/// Which states of the root path `RootConfig::prepare` accepts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RootMode {
+    /// Create a missing root, adopt an empty directory or reopen an existing
+    /// root.
+    #[default]
+    OpenOrCreate,
+    /// Create the root directory; it must not exist.
+    CreateNew,
+    /// Mark an existing empty directory as the root.
+    AdoptEmpty,
+    /// Reopen a directory already marked as a root.
+    Reopen,
+}
+
+/// An error preparing the virtualization root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootError {
+    /// The root does not exist, but the mode needs an existing directory.
+    NotFound,
+    /// The root exists, but the mode creates a new directory, or it is
+    /// already marked and the mode adopts an unmarked one.
+    AlreadyExists,
+    /// The root path exists and is not a directory.
+    NotADirectory,
+    /// The root is a directory with contents but no marker.
+    NotEmpty,
+    /// The root is a directory without a marker, but the mode reopens a root.
+    NotARoot,
+    /// The marker of an existing root could not be used.
+    Marker(MarkerError),
+    /// A file system operation failed.
+    Io(Error),
+    /// The directory could not be marked as a virtualization root.
+    MarkFailed(Error),
+}
+
+impl RootError {
+    /// The `HRESULT` best describing the error.
+    pub fn hresult(&self) -> crate::error::HRESULT {
+        Error::from(self.clone()).hresult()
+    }
+}
+
+impl fmt::Display for RootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RootError::NotFound => f.write_str("the root does not exist"),
+            RootError::AlreadyExists => f.write_str("the root already exists"),
+            RootError::NotADirectory => f.write_str("the root is not a directory"),
+            RootError::NotEmpty => f.write_str("the root is not empty and has no marker"),
+            RootError::NotARoot => f.write_str("the directory has no root marker"),
+            RootError::Marker(error) => error.fmt(f),
+            RootError::Io(error) => write!(f, "file system error: {}", error),
+            RootError::MarkFailed(error) => {
+                write!(f, "unable to mark the root as a placeholder: {}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RootError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RootError::Marker(error) => Some(error),
+            RootError::Io(error) | RootError::MarkFailed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RootError {
+    fn from(error: std::io::Error) -> Self {
+        RootError::Io(error.into())
+    }
+}
+
+impl From<MarkerError> for RootError {
+    fn from(error: MarkerError) -> Self {
+        RootError::Marker(error)
+    }
+}
+
+impl From<RootError> for Error {
+    fn from(error: RootError) -> Self {
+        let message = error.to_string();
+        match error {
+            RootError::NotFound | RootError::NotARoot => Error::not_found(),
+            RootError::AlreadyExists => Error::already_exists(),
+            RootError::NotADirectory => Error::not_a_directory(),
+            RootError::NotEmpty => Error::directory_not_empty(),
+            RootError::Marker(error) => return error.into(),
+            RootError::Io(error) | RootError::MarkFailed(error) => return error,
+        }
+        .with_message(message)
+    }
+}
+
+/// Marks a directory as a virtualization root, like
+/// `PrjMarkDirectoryAsPlaceholder`. Kept behind a trait so root preparation
+/// can be tested without ProjFS.
+pub trait MarkDirectory {
+    fn mark_directory_as_placeholder(&self, root: &Path, instance_id: Guid) -> Result<()>;
+}
+
+/// The state of a root path, as seen by `RootConfig::prepare`.
+enum RootState {
+    Missing,
+    Empty,
+    Marked,
+    NotEmpty,
+}
+
+/// The root marker a provider expects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootConfig {
+    mode: RootMode,
+    marker_file: PathBuf,
+    provider_name: String,
+    provider_version: String,
+    metadata: BTreeMap<String, String>,
+}
+
+impl RootConfig {
+    pub fn new<N: Into<String>, V: Into<String>>(provider_name: N, provider_version: V) -> Self {
+        RootConfig {
+            mode: RootMode::default(),
+            marker_file: PathBuf::from(MARKER_FILE),
+            provider_name: provider_name.into(),
+            provider_version: provider_version.into(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    pub fn mode(mut self, mode: RootMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the name of the marker file, relative to the root.
+    pub fn marker_file<P: Into<PathBuf>>(mut self, marker_file: P) -> Self {
+        self.marker_file = marker_file.into();
+        self
+    }
+
+    /// Adds a free-form value recorded in newly written markers.
+    pub fn metadata<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn provider_name(&self) -> &str {
+        &self.provider_name
+    }
+
+    /// Returns the path of the marker file in `root`.
+    pub fn marker_path(&self, root: &Path) -> PathBuf {
+        root.join(&self.marker_file)
+    }
+
+    /// Returns the metadata of a root newly marked with `instance_id`.
+    pub fn new_metadata(&self, instance_id: Guid) -> RootMetadata {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        RootMetadata {
+            schema_version: SCHEMA_VERSION,
+            instance_id,
+            provider_name: self.provider_name.clone(),
+            provider_version: self.provider_version.clone(),
+            created,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    /// Writes a new marker for `instance_id` into `root`.
+    pub fn create(
+        &self,
+        root: &Path,
+        instance_id: Guid,
+    ) -> std::result::Result<RootMetadata, MarkerError> {
+        let metadata = self.new_metadata(instance_id);
+        metadata.write(self.marker_path(root))?;
+        Ok(metadata)
+    }
+
+    /// Reads the marker of `root` and checks that it belongs to this provider.
+    /// Binary markers are rewritten as JSON owned by this provider.
+    pub fn open(&self, root: &Path) -> std::result::Result<RootMetadata, MarkerError> {
+        let path = self.marker_path(root);
+        let bytes = std::fs::read(&path)?;
+
+        // the lengths of the binary formats never fit a JSON marker, while a
+        // legacy GUID may well start with `{`
+        let binary = bytes.len() == GUID_LEN
+            || (bytes.len() == HEADER_LEN + GUID_LEN && bytes.starts_with(MAGIC));
+        if binary {
+            let marker = RootMarker::from_bytes(&bytes).map_err(|e| MarkerError::Corrupt {
+                reason: e.to_string(),
+            })?;
+            return self.create(root, marker.instance_id());
+        }
+
+        let json = std::str::from_utf8(&bytes).map_err(|e| MarkerError::Corrupt {
+            reason: e.to_string(),
+        })?;
+        let metadata = RootMetadata::from_json(json)?;
+        if metadata.provider_name != self.provider_name {
+            return Err(MarkerError::ForeignProvider {
+                expected: self.provider_name.clone(),
+                found: metadata.provider_name,
+            });
+        }
+        Ok(metadata)
+    }
+
+    /// Makes `root` a virtualization root according to the mode, marking new
+    /// roots through `os`, and returns its metadata.
+    pub fn prepare(
+        &self,
+        root: &Path,
+        os: &dyn MarkDirectory,
+    ) -> std::result::Result<RootMetadata, RootError> {
+        let state = self.root_state(root)?;
+        match (self.mode, state) {
+            (RootMode::OpenOrCreate, RootState::Missing)
+            | (RootMode::CreateNew, RootState::Missing) => {
+                std::fs::create_dir(root)?;
+                let metadata = self.mark(root, os);
+                if metadata.is_err() {
+                    let _ = std::fs::remove_dir(root);
+                }
+                metadata
+            }
+            (RootMode::OpenOrCreate, RootState::Empty)
+            | (RootMode::AdoptEmpty, RootState::Empty) => self.mark(root, os),
+            (RootMode::OpenOrCreate, RootState::Marked) | (RootMode::Reopen, RootState::Marked) => {
+                Ok(self.open(root)?)
+            }
+            (RootMode::CreateNew, _) | (RootMode::AdoptEmpty, RootState::Marked) => {
+                Err(RootError::AlreadyExists)
+            }
+            (_, RootState::Missing) => Err(RootError::NotFound),
+            (RootMode::Reopen, _) => Err(RootError::NotARoot),
+            (_, _) => Err(RootError::NotEmpty),
+        }
+    }
+
+    fn root_state(&self, root: &Path) -> std::result::Result<RootState, RootError> {
+        let metadata = match std::fs::metadata(root) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(RootState::Missing),
+            Err(e) => return Err(e.into()),
+        };
+        if !metadata.is_dir() {
+            return Err(RootError::NotADirectory);
+        }
+
+        if self.marker_path(root).exists() {
+            Ok(RootState::Marked)
+        } else if std::fs::read_dir(root)?.next().is_none() {
+            Ok(RootState::Empty)
+        } else {
+            Ok(RootState::NotEmpty)
+        }
+    }
+
+    fn mark(
+        &self,
+        root: &Path,
+        os: &dyn MarkDirectory,
+    ) -> std::result::Result<RootMetadata, RootError> {
+        let instance_id = Guid::new_v4().map_err(RootError::Io)?;
+        let metadata = self.create(root, instance_id)?;
+        if let Err(error) = os.mark_directory_as_placeholder(root, instance_id) {
+            let _ = std::fs::remove_file(self.marker_path(root));
+            return Err(RootError::MarkFailed(error));
+        }
+        Ok(metadata)
+    }
+}
+
+#[test]
+fn test_marker_bytes() {
+    let id: Guid = "{6B29FC40-CA47-1067-B31D-00DD010662DA}".parse().unwrap();
+    let mut bytes = b"PJID\x01L".to_vec();
+    bytes.extend_from_slice(&id.to_bytes_le());
+    assert_eq!(RootMarker::from_bytes(&bytes).unwrap().instance_id(), id);
+
+    let mut big_endian = bytes[..6].to_vec();
+    big_endian[5] = b'B';
+    big_endian.extend_from_slice(&id.to_bytes_be());
+    assert_eq!(
+        RootMarker::from_bytes(&big_endian).unwrap().instance_id(),
+        id
+    );
+
+    let legacy = RootMarker::from_bytes(&id.to_bytes_be()).unwrap();
+    assert_eq!(legacy.instance_id(), id);
+
+    let mut future = bytes.clone();
+    future[4] = 2;
+    let mut bad_order = bytes.clone();
+    bad_order[5] = b'X';
+    for invalid in &[&b""[..], &bytes[..21], &future, &bad_order, &[0; 22]] {
+        assert!(RootMarker::from_bytes(invalid).is_err());
+    }
+}
+
+#[cfg(test)]
+use crate::testutil::TempDir;
+
+#[test]
+fn test_metadata_json() {
+    let config = RootConfig::new("regfs", "0.1.0").metadata("hive", "HKLM");
+    let id: Guid = "{6B29FC40-CA47-1067-B31D-00DD010662DA}".parse().unwrap();
+    let metadata = config.new_metadata(id);
+    assert_eq!(metadata.schema_version, SCHEMA_VERSION);
+    assert_eq!(metadata.metadata["hive"], "HKLM");
+
+    let json = metadata.to_json();
+    assert!(json.contains("\"{6B29FC40-CA47-1067-B31D-00DD010662DA}\""));
+    assert_eq!(RootMetadata::from_json(&json), Ok(metadata));
+
+    let minimal = r#"{"schema_version": 1, "instance_id": "{6B29FC40-CA47-1067-B31D-00DD010662DA}",
+        "provider_name": "regfs", "provider_version": "0.1.0", "created": 0}"#;
+    let metadata = RootMetadata::from_json(minimal).unwrap();
+    assert!(metadata.metadata.is_empty());
+    assert_eq!(metadata.created_time(), UNIX_EPOCH);
+
+    assert_eq!(
+        RootMetadata::from_json(&minimal.replace("\"schema_version\": 1", "\"schema_version\": 2")),
+        Err(MarkerError::UnsupportedVersion { found: 2 })
+    );
+    for corrupt in &[
+        "",
+        "{",
+        &minimal.replace("{6B29FC40", "{6B29FC4"),
+        &minimal.replace("\"created\": 0", "\"created\": -1"),
+    ] {
+        match RootMetadata::from_json(corrupt) {
+            Err(MarkerError::Corrupt { .. }) => {}
+            other => panic!("{:?} parsed as {:?}", corrupt, other),
+        }
+    }
+}
+
+#[test]
+fn test_config_open() {
+    let dir = TempDir::new("root");
+    let root = dir.path();
+    let config = RootConfig::new("regfs", "0.1.0");
+    let id = Guid::new_v4().unwrap();
+
+    match config.open(root) {
+        Err(MarkerError::Io(_)) => {}
+        other => panic!("{:?}", other),
+    }
+
+    let created = config.create(root, id).unwrap();
+    assert_eq!(config.open(root), Ok(created));
+
+    assert_eq!(
+        RootConfig::new("mirror", "1.0").open(root),
+        Err(MarkerError::ForeignProvider {
+            expected: "mirror".to_string(),
+            found: "regfs".to_string(),
+        })
+    );
+
+    std::fs::write(config.marker_path(root), "{ not json").unwrap();
+    match config.open(root) {
+        Err(error @ MarkerError::Corrupt { .. }) => {
+            assert_eq!(
+                Error::from(error).hresult(),
+                Error::invalid_argument().hresult()
+            )
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn test_config_open_migrates_binary_markers() {
+    let dir = TempDir::new("root");
+    let root = dir.path();
+    let config = RootConfig::new("regfs", "0.1.0").marker_file(".regfsId");
+    let path = root.join(".regfsId");
+
+    let legacy = Guid::new_v4().unwrap();
+    std::fs::write(&path, legacy.to_bytes_be()).unwrap();
+    let metadata = config.open(root).unwrap();
+    assert_eq!(metadata.instance_id, legacy);
+    assert_eq!(metadata.provider_name, "regfs");
+    assert_eq!(RootMetadata::read(&path), Ok(metadata));
+
+    let brace: Guid = "{7B29FC40-CA47-1067-B31D-00DD010662DA}".parse().unwrap();
+    std::fs::write(&path, brace.to_bytes_be()).unwrap();
+    assert_eq!(config.open(root).unwrap().instance_id, brace);
+
+    let versioned = Guid::new_v4().unwrap();
+    let mut bytes = b"PJID\x01L".to_vec();
+    bytes.extend_from_slice(&versioned.to_bytes_le());
+    std::fs::write(&path, &bytes).unwrap();
+    assert_eq!(config.open(root).unwrap().instance_id, versioned);
+
+    bytes[4] = 2;
+    std::fs::write(&path, bytes).unwrap();
+    assert!(matches!(
+        config.open(root),
+        Err(MarkerError::Corrupt { .. })
+    ));
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct FakeMarkDirectory {
+    marked: std::sync::Mutex<Vec<(PathBuf, Guid)>>,
+    fail: bool,
+}
+
+#[cfg(test)]
+impl MarkDirectory for FakeMarkDirectory {
+    fn mark_directory_as_placeholder(&self, root: &Path, instance_id: Guid) -> Result<()> {
+        if self.fail {
+            return Err(Error::access_denied());
+        }
+        self.marked
+            .lock()
+            .unwrap()
+            .push((root.to_owned(), instance_id));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_prepare_modes() {
+    let parent = TempDir::new("root");
+    let missing = parent.join("missing");
+    let empty = parent.join("empty");
+    let full = parent.join("full");
+    let file = parent.join("file");
+    std::fs::create_dir(&empty).unwrap();
+    std::fs::create_dir(&full).unwrap();
+    std::fs::write(full.join("data"), "data").unwrap();
+    std::fs::write(&file, "data").unwrap();
+
+    let config = |mode| RootConfig::new("regfs", "0.1.0").mode(mode);
+    let os = FakeMarkDirectory::default();
+
+    for mode in &[
+        RootMode::OpenOrCreate,
+        RootMode::CreateNew,
+        RootMode::AdoptEmpty,
+        RootMode::Reopen,
+    ] {
+        assert_eq!(
+            config(*mode).prepare(&file, &os),
+            Err(RootError::NotADirectory)
+        );
+        assert_eq!(config(*mode).prepare(&full, &os), {
+            match mode {
+                RootMode::CreateNew => Err(RootError::AlreadyExists),
+                RootMode::Reopen => Err(RootError::NotARoot),
+                _ => Err(RootError::NotEmpty),
+            }
+        });
+    }
+
+    assert_eq!(
+        config(RootMode::AdoptEmpty).prepare(&missing, &os),
+        Err(RootError::NotFound)
+    );
+    assert_eq!(
+        config(RootMode::Reopen).prepare(&missing, &os),
+        Err(RootError::NotFound)
+    );
+    assert_eq!(
+        config(RootMode::CreateNew).prepare(&empty, &os),
+        Err(RootError::AlreadyExists)
+    );
+    assert_eq!(
+        config(RootMode::Reopen).prepare(&empty, &os),
+        Err(RootError::NotARoot)
+    );
+    assert!(os.marked.lock().unwrap().is_empty());
+
+    let created = config(RootMode::CreateNew).prepare(&missing, &os).unwrap();
+    let adopted = config(RootMode::AdoptEmpty).prepare(&empty, &os).unwrap();
+    assert_eq!(
+        *os.marked.lock().unwrap(),
+        [
+            (missing.clone(), created.instance_id),
+            (empty.clone(), adopted.instance_id)
+        ]
+    );
+
+    assert_eq!(config(RootMode::Reopen).prepare(&missing, &os), Ok(created));
+    assert_eq!(
+        config(RootMode::OpenOrCreate).prepare(&empty, &os),
+        Ok(adopted)
+    );
+    assert_eq!(
+        config(RootMode::AdoptEmpty).prepare(&empty, &os),
+        Err(RootError::AlreadyExists)
+    );
+    assert_eq!(os.marked.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn test_prepare_cleans_up_after_mark_failure() {
+    let parent = TempDir::new("root");
+    let missing = parent.join("missing");
+    let empty = parent.join("empty");
+    std::fs::create_dir(&empty).unwrap();
+
+    let config = RootConfig::new("regfs", "0.1.0");
+    let os = FakeMarkDirectory {
+        fail: true,
+        ..Default::default()
+    };
+
+    let error = config.prepare(&missing, &os).unwrap_err();
+    assert_eq!(error, RootError::MarkFailed(Error::access_denied()));
+    assert_eq!(error.hresult(), Error::access_denied().hresult());
+    assert!(!missing.exists());
+
+    assert!(config.prepare(&empty, &os).is_err());
+    assert!(empty.exists());
+    assert!(!config.marker_path(&empty).exists());
+}
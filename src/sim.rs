@@ -0,0 +1,570 @@
+//! An in-process stand-in for ProjFS, to exercise providers on any platform.
+//!
+//! `SimHost` invokes the callbacks of a `ProviderT` the way the OS does:
+//! directory enumerations run as `start_dir_enum`, repeated `get_dir_enum`
+//! calls with a bounded buffer and `end_dir_enum`, all under one enumeration
+//! ID, and file reads request the placeholder first and then the contents in
+//! chunks. Placeholder and file data writes go through a `RecordingHandle`, so
+//! tests can check exactly what would have reached ProjFS.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::enumeration::DirEntry;
+use crate::error::{Error, Result};
+use crate::guid::Guid;
+use crate::handle::{self, RecordingHandle, Write};
+use crate::notification::Notification;
+use crate::provider::{
+    CallbackContext, CallbackFlags, DirEntryBuffer, FileBasicInfo, PlaceholderInfo, ProviderT,
+};
+use crate::sys::PRJ_NOTIFICATION_PARAMETERS;
+
+/// Drives a provider like a ProjFS virtualization instance would.
+#[derive(Debug)]
+pub struct SimHost<P> {
+    provider: P,
+    handle: Arc<RecordingHandle>,
+    process_id: u32,
+    process_image: Option<PathBuf>,
+    entries_per_call: usize,
+    chunk_size: u32,
+    next_command_id: AtomicI32,
+    next_guid: AtomicU32,
+}
+
+impl<P: ProviderT> SimHost<P> {
+    pub fn new(provider: P) -> Self {
+        SimHost {
+            provider,
+            handle: Arc::new(RecordingHandle::new()),
+            process_id: 0,
+            process_image: None,
+            entries_per_call: 16,
+            chunk_size: 64 * 1024,
+            next_command_id: AtomicI32::new(1),
+            next_guid: AtomicU32::new(1),
+        }
+    }
+
+    /// Reports every callback as triggered by the given process.
+    pub fn triggered_by<T: Into<PathBuf>>(mut self, process_id: u32, image: T) -> Self {
+        self.process_id = process_id;
+        self.process_image = Some(image.into());
+        self
+    }
+
+    /// Sets how many entries fit in the buffer of one `get_dir_enum` call.
+    pub fn entries_per_call(mut self, count: usize) -> Self {
+        self.entries_per_call = count;
+        self
+    }
+
+    /// Sets the largest range requested by one `get_file_data` call.
+    pub fn chunk_size(mut self, size: u32) -> Self {
+        self.chunk_size = size;
+        self
+    }
+
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+
+    /// Returns the handle recording the writes of the provider.
+    pub fn handle(&self) -> &RecordingHandle {
+        &self.handle
+    }
+
+    /// Returns the writes made so far and forgets them.
+    pub fn take_writes(&self) -> Vec<Write> {
+        self.handle.take_writes()
+    }
+
+    /// Starts an enumeration of the directory at `path`.
+    pub fn start_enumeration<T: AsRef<Path>>(&self, path: T) -> Result<Enumeration<'_, P>> {
+        let path = path.as_ref().to_owned();
+        let id = self.new_guid();
+
+        self.provider
+            .start_dir_enum(&self.context(&path, CallbackFlags::empty()), id)?;
+
+        Ok(Enumeration {
+            host: self,
+            path,
+            id,
+            ended: false,
+        })
+    }
+
+    /// Lists the directory at `path` in one enumeration, passing
+    /// `search_expression` to every `get_dir_enum` call like the OS does.
+    pub fn read_dir<T: AsRef<Path>>(
+        &self,
+        path: T,
+        search_expression: Option<&OsStr>,
+    ) -> Result<Vec<DirEntry>> {
+        let mut enumeration = self.start_enumeration(path)?;
+        let mut entries = Vec::new();
+
+        loop {
+            let batch = enumeration.next_batch(search_expression, CallbackFlags::empty())?;
+            if batch.is_empty() {
+                break;
+            }
+            entries.extend(batch);
+        }
+
+        enumeration.end()?;
+        Ok(entries)
+    }
+
+    /// Requests the placeholder of `path` and writes it, like the first access
+    /// to a name that is not on disk yet.
+    pub fn placeholder_info<T: AsRef<Path>>(&self, path: T) -> Result<PlaceholderInfo> {
+        let context = self.context(path.as_ref(), CallbackFlags::empty());
+        let info = self.provider.get_placeholder_info(&context)?;
+
+        context
+            .handle
+            .write_placeholder_info(&context.file_path, &info)?;
+        Ok(info)
+    }
+
+    /// Reads the file at `path` like its first open does: the placeholder is
+    /// requested, then the contents in ranges of at most `chunk_size` bytes,
+    /// all written to one data stream. The provider must return exactly the
+    /// bytes requested.
+    pub fn read_file<T: AsRef<Path>>(&self, path: T) -> Result<Vec<u8>> {
+        let path = path.as_ref();
+        let info = self.placeholder_info(path)?.basic_info;
+        if info.is_directory {
+            return Err(
+                Error::invalid_argument().with_message(format!("{:?} is a directory", path))
+            );
+        }
+
+        let data_stream_id = self.new_guid();
+        let mut contents = Vec::with_capacity(info.file_size as usize);
+        let mut offset = 0;
+
+        while offset < info.file_size {
+            let length = (info.file_size - offset).min(u64::from(self.chunk_size)) as u32;
+            let mut context = self.context(path, CallbackFlags::empty());
+            context.data_stream_id = data_stream_id;
+
+            let bytes = self.provider.get_file_data(&context, offset, length)?;
+            if bytes.len() != length as usize {
+                return Err(Error::other(format!(
+                    "requested {} bytes of {:?} at offset {}, got {}",
+                    length,
+                    path,
+                    offset,
+                    bytes.len()
+                )));
+            }
+
+            handle::write_bytes(&*context.handle, data_stream_id, offset, &bytes)?;
+            contents.extend_from_slice(&bytes);
+            offset += u64::from(length);
+        }
+
+        Ok(contents)
+    }
+
+    /// Sends a notification about `path` and applies the provider's verdict
+    /// like ProjFS would see it: a denial is returned as the error, and a new
+    /// notification mask is written into the returned parameters.
+    pub fn notify<T: AsRef<Path>>(
+        &self,
+        path: T,
+        is_directory: bool,
+        notification: Notification,
+    ) -> Result<PRJ_NOTIFICATION_PARAMETERS> {
+        let kind = notification.kind();
+        let mut parameters = PRJ_NOTIFICATION_PARAMETERS::default();
+        let verdict = self.provider.notify(
+            &self.context(path.as_ref(), CallbackFlags::empty()),
+            is_directory,
+            notification,
+        );
+
+        verdict.apply(kind, &mut parameters)?;
+        Ok(parameters)
+    }
+
+    pub fn query_file_name<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        self.provider
+            .query_file_name(&self.context(path.as_ref(), CallbackFlags::empty()))
+    }
+
+    /// Cancels the command `command_id` on `path`, as returned from
+    /// `last_command_id`.
+    pub fn cancel_command<T: AsRef<Path>>(&self, path: T, command_id: i32) {
+        let mut context = self.context(path.as_ref(), CallbackFlags::empty());
+        context.command_id = command_id;
+        self.provider.cancel_command(&context);
+    }
+
+    /// Returns the command ID of the latest callback.
+    pub fn last_command_id(&self) -> i32 {
+        self.next_command_id.load(Ordering::SeqCst) - 1
+    }
+
+    fn context(&self, path: &Path, flags: CallbackFlags) -> CallbackContext {
+        CallbackContext {
+            file_path: path.to_owned(),
+            triggering_process_id: self.process_id,
+            triggering_process_image: self.process_image.clone(),
+            command_id: self.next_command_id.fetch_add(1, Ordering::SeqCst),
+            data_stream_id: Guid::default(),
+            flags,
+            handle: self.handle.clone(),
+        }
+    }
+
+    fn new_guid(&self) -> Guid {
+        let id = self.next_guid.fetch_add(1, Ordering::SeqCst);
+        Guid::from_fields(id, 0, 0, [0; 8])
+    }
+}
+
+/// A directory enumeration in progress, ended when dropped.
+#[derive(Debug)]
+pub struct Enumeration<'a, P: ProviderT> {
+    host: &'a SimHost<P>,
+    path: PathBuf,
+    id: Guid,
+    ended: bool,
+}
+
+impl<'a, P: ProviderT> Enumeration<'a, P> {
+    pub fn id(&self) -> Guid {
+        self.id
+    }
+
+    /// Issues one `get_dir_enum` call and returns the entries it produced.
+    /// An empty result means the enumeration is complete.
+    pub fn next_batch(
+        &mut self,
+        search_expression: Option<&OsStr>,
+        flags: CallbackFlags,
+    ) -> Result<Vec<DirEntry>> {
+        let mut buffer = SimBuffer {
+            capacity: self.host.entries_per_call,
+            entries: Vec::new(),
+        };
+
+        self.host.provider.get_dir_enum(
+            &self.host.context(&self.path, flags),
+            self.id,
+            search_expression,
+            &mut buffer,
+        )?;
+        Ok(buffer.entries)
+    }
+
+    pub fn end(mut self) -> Result<()> {
+        self.ended = true;
+        self.host.provider.end_dir_enum(
+            &self.host.context(&self.path, CallbackFlags::empty()),
+            self.id,
+        )
+    }
+}
+
+impl<P: ProviderT> Drop for Enumeration<'_, P> {
+    fn drop(&mut self) {
+        if !self.ended {
+            let context = self.host.context(&self.path, CallbackFlags::empty());
+            let _ = self.host.provider.end_dir_enum(&context, self.id);
+        }
+    }
+}
+
+struct SimBuffer {
+    capacity: usize,
+    entries: Vec<DirEntry>,
+}
+
+impl DirEntryBuffer for SimBuffer {
+    fn fill(&mut self, name: &OsStr, info: &FileBasicInfo) -> bool {
+        if self.entries.len() == self.capacity {
+            return false;
+        }
+        self.entries.push(DirEntry {
+            name: name.to_owned(),
+            info: *info,
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+use crate::notification::Verdict;
+
+#[cfg(test)]
+struct TreeProvider {
+    sessions: crate::enumeration::SessionTable,
+    files: std::collections::BTreeMap<PathBuf, Vec<u8>>,
+    log: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl TreeProvider {
+    /// A registry-like tree: `HKEY_LOCAL_MACHINE\Foo` and
+    /// `HKEY_LOCAL_MACHINE\Software\Bar`.
+    fn new() -> Self {
+        let hklm = Path::new("HKEY_LOCAL_MACHINE");
+        let files = vec![
+            (hklm.join("Foo"), b"foo value".to_vec()),
+            (hklm.join("Software").join("Bar"), b"bar".to_vec()),
+        ];
+
+        TreeProvider {
+            sessions: Default::default(),
+            files: files.into_iter().collect(),
+            log: Default::default(),
+        }
+    }
+
+    fn is_directory(&self, path: &Path) -> bool {
+        self.files
+            .keys()
+            .any(|file| file.starts_with(path) && file != path)
+    }
+}
+
+#[cfg(test)]
+impl crate::enumeration::DirectorySource for TreeProvider {
+    fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        if !self.is_directory(path) {
+            return Err(Error::not_found());
+        }
+
+        let mut entries: Vec<DirEntry> = Vec::new();
+        for (file, contents) in &self.files {
+            let rest = match file.strip_prefix(path) {
+                Ok(rest) => rest,
+                Err(_) => continue,
+            };
+            let mut components = rest.components();
+            let name = components.next().unwrap().as_os_str();
+            if entries.iter().any(|entry| entry.name == name) {
+                continue;
+            }
+            entries.push(match components.next() {
+                Some(_) => DirEntry::directory(name),
+                None => DirEntry::file(name, contents.len() as u64),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+impl ProviderT for TreeProvider {
+    fn start_dir_enum(&self, context: &CallbackContext, enumeration_id: Guid) -> Result<()> {
+        self.sessions.start(enumeration_id, &context.file_path);
+        Ok(())
+    }
+
+    fn end_dir_enum(&self, _context: &CallbackContext, enumeration_id: Guid) -> Result<()> {
+        self.sessions.end(enumeration_id);
+        Ok(())
+    }
+
+    fn get_dir_enum(
+        &self,
+        context: &CallbackContext,
+        enumeration_id: Guid,
+        search_expression: Option<&OsStr>,
+        buffer: &mut dyn DirEntryBuffer,
+    ) -> Result<()> {
+        self.sessions
+            .fill(self, context, enumeration_id, search_expression, buffer)
+    }
+
+    fn get_placeholder_info(&self, context: &CallbackContext) -> Result<PlaceholderInfo> {
+        match self.files.get(&context.file_path) {
+            Some(contents) => Ok(PlaceholderInfo::file(contents.len() as u64)),
+            None if self.is_directory(&context.file_path) => Ok(PlaceholderInfo::directory()),
+            None => Err(Error::not_found()),
+        }
+    }
+
+    fn get_file_data(
+        &self,
+        context: &CallbackContext,
+        offset: u64,
+        length: u32,
+    ) -> Result<Vec<u8>> {
+        let contents = self
+            .files
+            .get(&context.file_path)
+            .ok_or_else(Error::not_found)?;
+        let start = offset as usize;
+        Ok(contents[start..start + length as usize].to_vec())
+    }
+
+    fn notify(
+        &self,
+        context: &CallbackContext,
+        _is_directory: bool,
+        notification: Notification,
+    ) -> Verdict {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("notify {:?} {:?}", context.file_path, notification));
+        match notification {
+            Notification::PreDelete => Verdict::Deny(Error::access_denied()),
+            Notification::FileOpened => {
+                Verdict::AllowWithMask(crate::option::NotificationType::PRE_DELETE)
+            }
+            _ => Verdict::Allow,
+        }
+    }
+
+    fn query_file_name(&self, context: &CallbackContext) -> Result<()> {
+        if self.files.contains_key(&context.file_path) || self.is_directory(&context.file_path) {
+            Ok(())
+        } else {
+            Err(Error::not_found())
+        }
+    }
+
+    fn cancel_command(&self, context: &CallbackContext) {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("cancel {}", context.command_id));
+    }
+}
+
+#[test]
+fn test_enumerate_tree() {
+    let host = SimHost::new(TreeProvider::new()).entries_per_call(1);
+    let names = |entries| crate::testutil::entry_names(entries, |info| info.is_directory);
+
+    assert_eq!(
+        names(host.read_dir("", None).unwrap()),
+        [("HKEY_LOCAL_MACHINE".into(), true)]
+    );
+    assert_eq!(
+        names(host.read_dir("HKEY_LOCAL_MACHINE", None).unwrap()),
+        [("Foo".into(), false), ("Software".into(), true)]
+    );
+    assert_eq!(
+        names(
+            host.read_dir("HKEY_LOCAL_MACHINE", Some(OsStr::new("s*")))
+                .unwrap()
+        ),
+        [("Software".into(), true)]
+    );
+    assert_eq!(
+        host.read_dir("HKEY_CURRENT_USER", None).unwrap_err(),
+        Error::not_found()
+    );
+
+    // every enumeration was ended, including the failed one
+    assert!(host.provider().sessions.is_empty());
+    assert!(host.take_writes().is_empty());
+}
+
+#[test]
+fn test_enumeration_restart_and_drop() {
+    let host = SimHost::new(TreeProvider::new());
+    let mut enumeration = host.start_enumeration("HKEY_LOCAL_MACHINE").unwrap();
+
+    let first = enumeration
+        .next_batch(Some(OsStr::new("foo")), CallbackFlags::empty())
+        .unwrap();
+    assert_eq!(first, [DirEntry::file("Foo", 9)]);
+    assert!(enumeration
+        .next_batch(None, CallbackFlags::empty())
+        .unwrap()
+        .is_empty());
+
+    let restarted = enumeration
+        .next_batch(Some(OsStr::new("*")), CallbackFlags::ENUM_RESTART_SCAN)
+        .unwrap();
+    assert_eq!(restarted.len(), 2);
+
+    assert_eq!(host.provider().sessions.len(), 1);
+    drop(enumeration);
+    assert!(host.provider().sessions.is_empty());
+}
+
+#[test]
+fn test_open_file() {
+    let host = SimHost::new(TreeProvider::new()).chunk_size(4);
+    let foo = Path::new("HKEY_LOCAL_MACHINE").join("Foo");
+
+    assert_eq!(host.read_file(&foo).unwrap(), b"foo value");
+
+    let writes = host.take_writes();
+    assert_eq!(
+        writes[0],
+        Write::PlaceholderInfo {
+            path: foo.clone(),
+            info: PlaceholderInfo::file(9),
+        }
+    );
+    let chunks: Vec<_> = writes[1..]
+        .iter()
+        .map(|write| match write {
+            Write::FileData { offset, data, .. } => (*offset, data.as_slice()),
+            other => panic!("unexpected write {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        chunks,
+        [(0, &b"foo "[..]), (4, &b"valu"[..]), (8, &b"e"[..])]
+    );
+
+    assert!(host.read_file("HKEY_LOCAL_MACHINE").is_err());
+    assert_eq!(
+        host.placeholder_info("HKEY_LOCAL_MACHINE").unwrap(),
+        PlaceholderInfo::directory()
+    );
+    assert_eq!(
+        host.read_file(Path::new("HKEY_LOCAL_MACHINE").join("Missing"))
+            .unwrap_err(),
+        Error::not_found()
+    );
+}
+
+#[test]
+fn test_notifications_and_cancellation() {
+    let host = SimHost::new(TreeProvider::new()).triggered_by(42, "notepad.exe");
+    let foo = Path::new("HKEY_LOCAL_MACHINE").join("Foo");
+
+    let parameters = host.notify(&foo, false, Notification::FileOpened).unwrap();
+    assert_eq!(
+        unsafe { parameters.PostCreate().NotificationMask },
+        crate::option::NotificationType::PRE_DELETE.into_raw()
+    );
+    let denied = host.notify(&foo, false, Notification::PreDelete);
+    assert_eq!(denied.unwrap_err(), Error::access_denied());
+    let parameters = host
+        .notify(&foo, false, Notification::PreConvertToFull)
+        .unwrap();
+    assert_eq!(unsafe { parameters.PostCreate().NotificationMask }, 0);
+    assert!(host.query_file_name(&foo).is_ok());
+    assert!(host.query_file_name("Missing").is_err());
+
+    let command_id = host.last_command_id();
+    host.cancel_command(&foo, command_id);
+
+    assert_eq!(
+        *host.provider().log.lock().unwrap(),
+        [
+            format!("notify {:?} FileOpened", foo),
+            format!("notify {:?} PreDelete", foo),
+            format!("notify {:?} PreConvertToFull", foo),
+            format!("cancel {}", command_id),
+        ]
+    );
+}
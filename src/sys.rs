@@ -0,0 +1,295 @@
+//! Raw ProjFS definitions.
+//!
+//! On Windows these are the `winapi` bindings. Elsewhere only the types and
+//! constants are mirrored, with the same `repr(C)` layout, so the callback
+//! plumbing builds and can be tested; none of the `Prj*` functions exist there.
+
+#[cfg(windows)]
+pub use winapi::{
+    ctypes::c_void,
+    shared::guiddef::GUID,
+    shared::ntdef::{BOOLEAN, LARGE_INTEGER, TRUE},
+    shared::winerror::S_OK,
+    um::projectedfslib::*,
+    um::winnt::{HRESULT, PCWSTR},
+};
+
+#[cfg(not(windows))]
+pub use self::mirror::*;
+
+// the unsafe accessors mirror winapi's union accessors, which are undocumented there too
+#[cfg(not(windows))]
+#[allow(non_camel_case_types, non_snake_case, clippy::missing_safety_doc)]
+mod mirror {
+    pub use std::ffi::c_void;
+
+    pub type BOOLEAN = u8;
+    pub const TRUE: BOOLEAN = 1;
+    pub type HRESULT = i32;
+    pub const S_OK: HRESULT = 0;
+    pub type PCWSTR = *const u16;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct GUID {
+        pub Data1: u32,
+        pub Data2: u16,
+        pub Data3: u16,
+        pub Data4: [u8; 8],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct LARGE_INTEGER(i64);
+
+    impl LARGE_INTEGER {
+        pub unsafe fn QuadPart(&self) -> &i64 {
+            &self.0
+        }
+
+        pub unsafe fn QuadPart_mut(&mut self) -> &mut i64 {
+            &mut self.0
+        }
+    }
+
+    pub enum PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT__ {}
+    pub type PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT = *mut PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT__;
+    pub enum PRJ_DIR_ENTRY_BUFFER_HANDLE__ {}
+    pub type PRJ_DIR_ENTRY_BUFFER_HANDLE = *mut PRJ_DIR_ENTRY_BUFFER_HANDLE__;
+
+    pub type PRJ_NOTIFY_TYPES = u32;
+    pub const PRJ_NOTIFY_NONE: PRJ_NOTIFY_TYPES = 0x0000_0000;
+    pub const PRJ_NOTIFY_SUPPRESS_NOTIFICATIONS: PRJ_NOTIFY_TYPES = 0x0000_0001;
+    pub const PRJ_NOTIFY_FILE_OPENED: PRJ_NOTIFY_TYPES = 0x0000_0002;
+    pub const PRJ_NOTIFY_NEW_FILE_CREATED: PRJ_NOTIFY_TYPES = 0x0000_0004;
+    pub const PRJ_NOTIFY_FILE_OVERWRITTEN: PRJ_NOTIFY_TYPES = 0x0000_0008;
+    pub const PRJ_NOTIFY_PRE_DELETE: PRJ_NOTIFY_TYPES = 0x0000_0010;
+    pub const PRJ_NOTIFY_PRE_RENAME: PRJ_NOTIFY_TYPES = 0x0000_0020;
+    pub const PRJ_NOTIFY_PRE_SET_HARDLINK: PRJ_NOTIFY_TYPES = 0x0000_0040;
+    pub const PRJ_NOTIFY_FILE_RENAMED: PRJ_NOTIFY_TYPES = 0x0000_0080;
+    pub const PRJ_NOTIFY_HARDLINK_CREATED: PRJ_NOTIFY_TYPES = 0x0000_0100;
+    pub const PRJ_NOTIFY_FILE_HANDLE_CLOSED_NO_MODIFICATION: PRJ_NOTIFY_TYPES = 0x0000_0200;
+    pub const PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_MODIFIED: PRJ_NOTIFY_TYPES = 0x0000_0400;
+    pub const PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED: PRJ_NOTIFY_TYPES = 0x0000_0800;
+    pub const PRJ_NOTIFY_FILE_PRE_CONVERT_TO_FULL: PRJ_NOTIFY_TYPES = 0x0000_1000;
+    pub const PRJ_NOTIFY_USE_EXISTING_MASK: PRJ_NOTIFY_TYPES = 0xFFFF_FFFF;
+
+    pub type PRJ_NOTIFICATION = u32;
+    pub const PRJ_NOTIFICATION_FILE_OPENED: PRJ_NOTIFICATION = 0x0000_0002;
+    pub const PRJ_NOTIFICATION_NEW_FILE_CREATED: PRJ_NOTIFICATION = 0x0000_0004;
+    pub const PRJ_NOTIFICATION_FILE_OVERWRITTEN: PRJ_NOTIFICATION = 0x0000_0008;
+    pub const PRJ_NOTIFICATION_PRE_DELETE: PRJ_NOTIFICATION = 0x0000_0010;
+    pub const PRJ_NOTIFICATION_PRE_RENAME: PRJ_NOTIFICATION = 0x0000_0020;
+    pub const PRJ_NOTIFICATION_PRE_SET_HARDLINK: PRJ_NOTIFICATION = 0x0000_0040;
+    pub const PRJ_NOTIFICATION_FILE_RENAMED: PRJ_NOTIFICATION = 0x0000_0080;
+    pub const PRJ_NOTIFICATION_HARDLINK_CREATED: PRJ_NOTIFICATION = 0x0000_0100;
+    pub const PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_NO_MODIFICATION: PRJ_NOTIFICATION = 0x0000_0200;
+    pub const PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED: PRJ_NOTIFICATION = 0x0000_0400;
+    pub const PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_DELETED: PRJ_NOTIFICATION = 0x0000_0800;
+    pub const PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL: PRJ_NOTIFICATION = 0x0000_1000;
+
+    pub type PRJ_STARTVIRTUALIZING_FLAGS = u32;
+    pub const PRJ_FLAG_NONE: PRJ_STARTVIRTUALIZING_FLAGS = 0x0000_0000;
+    pub const PRJ_FLAG_USE_NEGATIVE_PATH_CACHE: PRJ_STARTVIRTUALIZING_FLAGS = 0x0000_0001;
+
+    pub type PRJ_CALLBACK_DATA_FLAGS = u32;
+    pub const PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN: PRJ_CALLBACK_DATA_FLAGS = 0x0000_0001;
+    pub const PRJ_CB_DATA_FLAG_ENUM_RETURN_SINGLE_ENTRY: PRJ_CALLBACK_DATA_FLAGS = 0x0000_0002;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct PRJ_NOTIFICATION_MAPPING {
+        pub NotificationBitMask: PRJ_NOTIFY_TYPES,
+        pub NotificationRoot: PCWSTR,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct PRJ_STARTVIRTUALIZING_OPTIONS {
+        pub Flags: PRJ_STARTVIRTUALIZING_FLAGS,
+        pub PoolThreadCount: u32,
+        pub ConcurrentThreadCount: u32,
+        pub NotificationMappings: *mut PRJ_NOTIFICATION_MAPPING,
+        pub NotificationMappingsCount: u32,
+    }
+
+    impl Default for PRJ_STARTVIRTUALIZING_OPTIONS {
+        fn default() -> Self {
+            unsafe { std::mem::zeroed() }
+        }
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct PRJ_PLACEHOLDER_VERSION_INFO {
+        pub ProviderID: [u8; 128],
+        pub ContentID: [u8; 128],
+    }
+
+    impl Default for PRJ_PLACEHOLDER_VERSION_INFO {
+        fn default() -> Self {
+            unsafe { std::mem::zeroed() }
+        }
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct PRJ_CALLBACK_DATA {
+        pub Size: u32,
+        pub Flags: PRJ_CALLBACK_DATA_FLAGS,
+        pub NamespaceVirtualizationContext: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        pub CommandId: i32,
+        pub FileId: GUID,
+        pub DataStreamId: GUID,
+        pub FilePathName: PCWSTR,
+        pub VersionInfo: *mut PRJ_PLACEHOLDER_VERSION_INFO,
+        pub TriggeringProcessId: u32,
+        pub TriggeringProcessImageFileName: PCWSTR,
+        pub InstanceContext: *mut c_void,
+    }
+
+    impl Default for PRJ_CALLBACK_DATA {
+        fn default() -> Self {
+            unsafe { std::mem::zeroed() }
+        }
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PRJ_FILE_BASIC_INFO {
+        pub IsDirectory: BOOLEAN,
+        pub FileSize: i64,
+        pub CreationTime: LARGE_INTEGER,
+        pub LastAccessTime: LARGE_INTEGER,
+        pub LastWriteTime: LARGE_INTEGER,
+        pub ChangeTime: LARGE_INTEGER,
+        pub FileAttributes: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PRJ_PLACEHOLDER_INFO_EaInformation {
+        pub EaBufferSize: u32,
+        pub OffsetToFirstEa: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PRJ_PLACEHOLDER_INFO_SecurityInformation {
+        pub SecurityBufferSize: u32,
+        pub OffsetToSecurityDescriptor: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PRJ_PLACEHOLDER_INFO_StreamsInformation {
+        pub StreamsInfoBufferSize: u32,
+        pub OffsetToFirstStreamInfo: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct PRJ_PLACEHOLDER_INFO {
+        pub FileBasicInfo: PRJ_FILE_BASIC_INFO,
+        pub EaInformation: PRJ_PLACEHOLDER_INFO_EaInformation,
+        pub SecurityInformation: PRJ_PLACEHOLDER_INFO_SecurityInformation,
+        pub StreamsInformation: PRJ_PLACEHOLDER_INFO_StreamsInformation,
+        pub VersionInfo: PRJ_PLACEHOLDER_VERSION_INFO,
+        pub VariableData: [u8; 1],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PRJ_NOTIFICATION_PARAMETERS_PostCreate {
+        pub NotificationMask: PRJ_NOTIFY_TYPES,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PRJ_NOTIFICATION_PARAMETERS_FileRenamed {
+        pub NotificationMask: PRJ_NOTIFY_TYPES,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PRJ_NOTIFICATION_PARAMETERS_FileDeletedOnHandleClose {
+        pub IsFileModified: BOOLEAN,
+    }
+
+    /// A union of the structs above, accessed like the `winapi` union.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PRJ_NOTIFICATION_PARAMETERS([u32; 1]);
+
+    impl PRJ_NOTIFICATION_PARAMETERS {
+        pub unsafe fn PostCreate(&self) -> &PRJ_NOTIFICATION_PARAMETERS_PostCreate {
+            &*(self as *const Self).cast()
+        }
+
+        pub unsafe fn PostCreate_mut(&mut self) -> &mut PRJ_NOTIFICATION_PARAMETERS_PostCreate {
+            &mut *(self as *mut Self).cast()
+        }
+
+        pub unsafe fn FileRenamed(&self) -> &PRJ_NOTIFICATION_PARAMETERS_FileRenamed {
+            &*(self as *const Self).cast()
+        }
+
+        pub unsafe fn FileRenamed_mut(&mut self) -> &mut PRJ_NOTIFICATION_PARAMETERS_FileRenamed {
+            &mut *(self as *mut Self).cast()
+        }
+
+        pub unsafe fn FileDeletedOnHandleClose(
+            &self,
+        ) -> &PRJ_NOTIFICATION_PARAMETERS_FileDeletedOnHandleClose {
+            &*(self as *const Self).cast()
+        }
+
+        pub unsafe fn FileDeletedOnHandleClose_mut(
+            &mut self,
+        ) -> &mut PRJ_NOTIFICATION_PARAMETERS_FileDeletedOnHandleClose {
+            &mut *(self as *mut Self).cast()
+        }
+    }
+
+    pub type PRJ_START_DIRECTORY_ENUMERATION_CB =
+        Option<unsafe extern "system" fn(*const PRJ_CALLBACK_DATA, *const GUID) -> HRESULT>;
+    pub type PRJ_END_DIRECTORY_ENUMERATION_CB =
+        Option<unsafe extern "system" fn(*const PRJ_CALLBACK_DATA, *const GUID) -> HRESULT>;
+    pub type PRJ_GET_DIRECTORY_ENUMERATION_CB = Option<
+        unsafe extern "system" fn(
+            *const PRJ_CALLBACK_DATA,
+            *const GUID,
+            PCWSTR,
+            PRJ_DIR_ENTRY_BUFFER_HANDLE,
+        ) -> HRESULT,
+    >;
+    pub type PRJ_GET_PLACEHOLDER_INFO_CB =
+        Option<unsafe extern "system" fn(*const PRJ_CALLBACK_DATA) -> HRESULT>;
+    pub type PRJ_GET_FILE_DATA_CB =
+        Option<unsafe extern "system" fn(*const PRJ_CALLBACK_DATA, u64, u32) -> HRESULT>;
+    pub type PRJ_QUERY_FILE_NAME_CB =
+        Option<unsafe extern "system" fn(*const PRJ_CALLBACK_DATA) -> HRESULT>;
+    pub type PRJ_NOTIFICATION_CB = Option<
+        unsafe extern "system" fn(
+            *const PRJ_CALLBACK_DATA,
+            BOOLEAN,
+            PRJ_NOTIFICATION,
+            PCWSTR,
+            *mut PRJ_NOTIFICATION_PARAMETERS,
+        ) -> HRESULT,
+    >;
+    pub type PRJ_CANCEL_COMMAND_CB = Option<unsafe extern "system" fn(*const PRJ_CALLBACK_DATA)>;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct PRJ_CALLBACKS {
+        pub StartDirectoryEnumerationCallback: PRJ_START_DIRECTORY_ENUMERATION_CB,
+        pub EndDirectoryEnumerationCallback: PRJ_END_DIRECTORY_ENUMERATION_CB,
+        pub GetDirectoryEnumerationCallback: PRJ_GET_DIRECTORY_ENUMERATION_CB,
+        pub GetPlaceholderInfoCallback: PRJ_GET_PLACEHOLDER_INFO_CB,
+        pub GetFileDataCallback: PRJ_GET_FILE_DATA_CB,
+        pub QueryFileNameCallback: PRJ_QUERY_FILE_NAME_CB,
+        pub NotificationCallback: PRJ_NOTIFICATION_CB,
+        pub CancelCommandCallback: PRJ_CANCEL_COMMAND_CB,
+    }
+}
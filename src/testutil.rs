@@ -0,0 +1,62 @@
+//! Fixtures shared by the unit tests.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::enumeration::DirEntry;
+use crate::guid::Guid;
+use crate::handle::RecordingHandle;
+use crate::provider::{CallbackContext, CallbackFlags, FileBasicInfo};
+
+/// A new directory under the temporary directory, removed with everything
+/// in it when dropped, also when the test panics.
+#[derive(Debug)]
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(prefix: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("prjfs-{}-{}", prefix, Guid::new_v4().unwrap()));
+        fs::create_dir(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub(crate) fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Pairs the name of each entry with what `f` takes from its metadata.
+pub(crate) fn entry_names<T, F>(entries: Vec<DirEntry>, f: F) -> Vec<(String, T)>
+where
+    F: Fn(&FileBasicInfo) -> T,
+{
+    entries
+        .into_iter()
+        .map(|entry| (entry.name.into_string().unwrap(), f(&entry.info)))
+        .collect()
+}
+
+/// A callback context for `path` recording into a fresh `RecordingHandle`.
+pub(crate) fn test_context<P: Into<PathBuf>>(path: P, flags: CallbackFlags) -> CallbackContext {
+    CallbackContext {
+        file_path: path.into(),
+        triggering_process_id: 0,
+        triggering_process_image: None,
+        command_id: 0,
+        data_stream_id: Guid::default(),
+        flags,
+        handle: Arc::new(RecordingHandle::new()),
+    }
+}
@@ -0,0 +1,53 @@
+use std::ffi::OsStr;
+
+/// Encodes `s` as UTF-16 without a terminating NUL.
+#[cfg(windows)]
+pub(crate) fn encode_wide(s: &OsStr) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    s.encode_wide().collect()
+}
+
+/// Encodes `s` as UTF-16 without a terminating NUL.
+#[cfg(not(windows))]
+pub(crate) fn encode_wide(s: &OsStr) -> Vec<u16> {
+    s.to_string_lossy().encode_utf16().collect()
+}
+
+/// Calls `f` with the UTF-16 code units of `s`, without collecting them.
+#[cfg(windows)]
+pub(crate) fn with_wide<T, F>(s: &OsStr, f: F) -> T
+where
+    F: FnOnce(&mut dyn Iterator<Item = u16>) -> T,
+{
+    use std::os::windows::ffi::OsStrExt;
+    f(&mut s.encode_wide())
+}
+
+/// Calls `f` with the UTF-16 code units of `s`, without collecting them.
+#[cfg(not(windows))]
+pub(crate) fn with_wide<T, F>(s: &OsStr, f: F) -> T
+where
+    F: FnOnce(&mut dyn Iterator<Item = u16>) -> T,
+{
+    f(&mut s.to_string_lossy().encode_utf16())
+}
+
+/// Maps a UTF-16 code unit to its uppercase form the way the NTFS upcase table
+/// does: only simple one-to-one mappings within the BMP are applied, everything
+/// else (including surrogates) is left untouched.
+pub(crate) fn upcase(unit: u16) -> u16 {
+    if unit < 0x80 {
+        return (unit as u8).to_ascii_uppercase() as u16;
+    }
+
+    let c = match std::char::from_u32(unit as u32) {
+        Some(c) => c,
+        None => return unit,
+    };
+
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(u), None) if (u as u32) <= 0xFFFF => u as u16,
+        _ => unit,
+    }
+}